@@ -0,0 +1,218 @@
+use axum::{
+    Json, Router,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    routing::{get, post, put},
+};
+
+use crate::{
+    AppState,
+    auth::Auth,
+    types::{
+        Bookmark, BookmarkFilter, BookmarkList, MyError, Page, SearchQuery, Tag, TagMerge,
+        TagRename,
+    },
+};
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/bookmarks", get(list_bookmarks).post(create_bookmark))
+        .route(
+            "/bookmarks/{id}",
+            get(get_bookmark)
+                .put(update_bookmark)
+                .delete(delete_bookmark),
+        )
+        .route("/tags", get(list_tags))
+        .route("/tags/merge", post(merge_tags))
+        .route("/tags/{name}", put(rename_tag).delete(delete_tag))
+        .route("/search", get(search))
+}
+
+// At most one of tag, q or d filters the list, p and limit page through it
+async fn list_bookmarks(
+    State(state): State<AppState>,
+    auth: Auth,
+    Query(filter): Query<BookmarkFilter>,
+) -> Result<Json<BookmarkList>, MyError> {
+    let page = filter.p.unwrap_or_default();
+    let mut p = Page {
+        p: Some(page),
+        limit: filter.limit,
+        sort: None,
+    };
+    let limit = p.checked(state.config.page_size)?;
+    let (bookmarks, total) = state
+        .db(auth, move |db| {
+            Ok(if let Some(tag) = &filter.tag {
+                db.get_bookmarks_by_tag(tag, &p)?
+            } else if let Some(query) = &filter.q {
+                db.search(
+                    &SearchQuery {
+                        sort: filter.sort.unwrap_or_default(),
+                        ..query.parse()?
+                    },
+                    &p,
+                    false,
+                )?
+            } else if let Some(date) = &filter.d {
+                db.get_bookmarks_by_date(date, &p)?
+            } else {
+                (db.get_page(&p)?, db.count_all()?)
+            })
+        })
+        .await?;
+
+    Ok(Json(BookmarkList {
+        bookmarks,
+        total,
+        page,
+        pages: total.div_ceil(limit),
+    }))
+}
+
+async fn create_bookmark(
+    State(state): State<AppState>,
+    auth: Auth,
+    Json(bookmark): Json<Bookmark>,
+) -> Result<(StatusCode, Json<Bookmark>), MyError> {
+    auth.require_write()?;
+    let bookmark = state
+        .db(auth, move |db| Ok(db.insert_bookmark(&bookmark)?))
+        .await
+        .map_err(MyError::for_bookmark)?;
+
+    Ok((StatusCode::CREATED, Json(bookmark)))
+}
+
+async fn get_bookmark(
+    State(state): State<AppState>,
+    auth: Auth,
+    Path(id): Path<i64>,
+) -> Result<Json<Bookmark>, MyError> {
+    Ok(Json(
+        state
+            .db(auth, move |db| Ok(db.get_bookmark_by_id(id)?))
+            .await
+            .map_err(MyError::for_bookmark)?,
+    ))
+}
+
+async fn update_bookmark(
+    State(state): State<AppState>,
+    auth: Auth,
+    Path(id): Path<i64>,
+    Json(bookmark): Json<Bookmark>,
+) -> Result<Json<Bookmark>, MyError> {
+    auth.require_write()?;
+    Ok(Json(
+        state
+            .db(auth, move |db| {
+                db.get_bookmark_by_id(id)?;
+                Ok(db.update_bookmark(&Bookmark { id, ..bookmark })?)
+            })
+            .await
+            .map_err(MyError::for_bookmark)?,
+    ))
+}
+
+async fn delete_bookmark(
+    State(state): State<AppState>,
+    auth: Auth,
+    Path(id): Path<i64>,
+) -> Result<Json<Vec<Bookmark>>, MyError> {
+    auth.require_write()?;
+    Ok(Json(
+        state
+            .db(auth, move |db| Ok(db.delete_bookmark(&[id])?))
+            .await
+            .map_err(MyError::for_bookmark)?,
+    ))
+}
+
+async fn list_tags(State(state): State<AppState>, auth: Auth) -> Result<Json<Vec<Tag>>, MyError> {
+    Ok(Json(state.db(auth, |db| Ok(db.list_tags()?)).await?))
+}
+
+// Renaming onto an existing tag is refused, merging is explicit
+async fn rename_tag(
+    State(state): State<AppState>,
+    auth: Auth,
+    Path(old): Path<String>,
+    Json(rename): Json<TagRename>,
+) -> Result<StatusCode, MyError> {
+    auth.require_write()?;
+    let new = rename.name.to_lowercase();
+    state
+        .db(auth, move |db| {
+            if !db.tag_exists(&old)? {
+                return Err(MyError::NotFound(format!("Tag '{old}' not found")));
+            }
+            if db.tag_exists(&new)? {
+                return Err(MyError::Conflict(format!(
+                    "Tag '{new}' already exists, merge instead"
+                )));
+            }
+            db.rename_tag(&old, &new)?;
+
+            Ok(StatusCode::NO_CONTENT)
+        })
+        .await
+}
+
+async fn merge_tags(
+    State(state): State<AppState>,
+    auth: Auth,
+    Json(merge): Json<TagMerge>,
+) -> Result<StatusCode, MyError> {
+    auth.require_write()?;
+    let into = merge.into.to_lowercase();
+    if into.is_empty() || merge.tags.is_empty() {
+        return Err(MyError::BadRequest(
+            "'tags' and 'into' must not be empty".to_string(),
+        ));
+    }
+    state
+        .db(auth, move |db| {
+            db.merge_tags(&merge.tags, &into)?;
+
+            Ok(StatusCode::NO_CONTENT)
+        })
+        .await
+}
+
+async fn delete_tag(
+    State(state): State<AppState>,
+    auth: Auth,
+    Path(name): Path<String>,
+) -> Result<StatusCode, MyError> {
+    auth.require_write()?;
+    state
+        .db(auth, move |db| match db.delete_tag(&name)? {
+            0 => Err(MyError::NotFound(format!("Tag '{name}' not found"))),
+            _ => Ok(StatusCode::NO_CONTENT),
+        })
+        .await
+}
+
+// The q filter of list_bookmarks on its own, q is required
+async fn search(
+    state: State<AppState>,
+    auth: Auth,
+    Query(filter): Query<BookmarkFilter>,
+) -> Result<Json<BookmarkList>, MyError> {
+    if filter.q.is_none() {
+        return Err(MyError::BadRequest("no 'q' parameter".to_string()));
+    }
+
+    list_bookmarks(
+        state,
+        auth,
+        Query(BookmarkFilter {
+            tag: None,
+            d: None,
+            ..filter
+        }),
+    )
+    .await
+}
@@ -0,0 +1,162 @@
+use argon2::{
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+    password_hash::{
+        SaltString,
+        rand_core::{OsRng, RngCore},
+    },
+};
+use axum::{
+    extract::FromRequestParts,
+    http::{
+        HeaderMap,
+        header::{AUTHORIZATION, COOKIE},
+        request::Parts,
+    },
+};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    AppState,
+    types::{MyError, Scope},
+};
+
+const SESSION_COOKIE: &str = "session";
+const SHOW_PRIVATE_COOKIE: &str = "show_private";
+pub const SESSION_MAX_AGE: i64 = 30 * 24 * 60 * 60;
+
+// Who is making the request. Private bookmarks are never shown to anonymous
+// requests, always to API tokens and to sessions that turned show_private on.
+#[derive(Debug, Clone, Copy)]
+pub enum Auth {
+    Anonymous,
+    Session { user_id: i64, show_private: bool },
+    Token(i64, Scope),
+}
+
+impl Auth {
+    pub fn authenticated(&self) -> bool {
+        !matches!(self, Auth::Anonymous)
+    }
+
+    // 0 for anonymous requests, the owner of bookmarks saved before the first login
+    pub fn user_id(&self) -> i64 {
+        match self {
+            Auth::Anonymous => 0,
+            Auth::Session { user_id, .. } | Auth::Token(user_id, _) => *user_id,
+        }
+    }
+
+    pub fn show_private(&self) -> bool {
+        match self {
+            Auth::Anonymous => false,
+            Auth::Session { show_private, .. } => *show_private,
+            Auth::Token(..) => true,
+        }
+    }
+
+    pub fn require_write(&self) -> Result<(), MyError> {
+        match self {
+            Auth::Session { .. } | Auth::Token(_, Scope::Write) => Ok(()),
+            Auth::Token(_, Scope::Read) => {
+                Err(MyError::Forbidden("API token is read-only".to_string()))
+            }
+            Auth::Anonymous => Err(MyError::Unauthorized("Log in to make changes".to_string())),
+        }
+    }
+}
+
+pub fn hash_password(password: &str) -> Result<String, MyError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+// Random token handed out once, only its hash is stored
+pub fn new_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    to_hex(&bytes)
+}
+
+pub fn token_hash(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+fn cookie(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(';'))
+        .find_map(|x| x.trim().strip_prefix(name)?.strip_prefix('='))
+        .map(String::from)
+}
+
+pub fn session_cookie(headers: &HeaderMap) -> Option<String> {
+    cookie(headers, SESSION_COOKIE)
+}
+
+// Lax keeps the cookie off cross-site form posts
+pub fn set_session_cookie(token: &str, max_age: i64) -> String {
+    format!("{SESSION_COOKIE}={token}; Path=/; HttpOnly; SameSite=Lax; Max-Age={max_age}")
+}
+
+// Only meaningful together with a session, anonymous requests ignore it
+pub fn set_show_private_cookie(show: bool) -> String {
+    format!(
+        "{SHOW_PRIVATE_COOKIE}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={SESSION_MAX_AGE}",
+        u8::from(show)
+    )
+}
+
+impl FromRequestParts<AppState> for Auth {
+    type Rejection = MyError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, MyError> {
+        if let Some(header) = parts.headers.get(AUTHORIZATION) {
+            let hash = header
+                .to_str()
+                .ok()
+                .and_then(|x| x.strip_prefix("Bearer "))
+                .map(token_hash)
+                .ok_or(MyError::Unauthorized(
+                    "Expected 'Authorization: Bearer <token>'".to_string(),
+                ))?;
+
+            return state
+                .db(Auth::Anonymous, move |db| Ok(db.get_token(&hash)?))
+                .await?
+                .map(|(user_id, scope)| Auth::Token(user_id, scope))
+                .ok_or(MyError::Unauthorized("Invalid API token".to_string()));
+        }
+
+        let Some(token) = session_cookie(&parts.headers) else {
+            return Ok(Auth::Anonymous);
+        };
+        let hash = token_hash(&token);
+
+        let show_private = cookie(&parts.headers, SHOW_PRIVATE_COOKIE).is_some_and(|x| x == "1");
+
+        // An expired or unknown session is just anonymous, the login page replaces it
+        Ok(state
+            .db(Auth::Anonymous, move |db| Ok(db.get_session(&hash)?))
+            .await?
+            .map_or(Auth::Anonymous, |user_id| Auth::Session {
+                user_id,
+                show_private,
+            }))
+    }
+}
@@ -0,0 +1,392 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use std::{io::Read, path::PathBuf};
+
+use crate::{
+    auth,
+    config::Config,
+    database::Db,
+    environment, handlers, import,
+    types::{Backup, Bookmark, MyError, Page, Scope, SearchQuery, SearchSort},
+};
+
+#[derive(Parser)]
+#[command(version, about = "Bookmarks and tabs memex")]
+pub struct Cli {
+    /// Config file [default: tabs-memex.toml if it exists]
+    #[arg(long, global = true, env = "TABS_MEMEX_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// SQLite database file [default: main.db3]
+    #[arg(long, global = true, env = "TABS_MEMEX_DB")]
+    pub db: Option<String>,
+    /// Login whose bookmarks are used [default: the only one]
+    #[arg(long, global = true, env = "TABS_MEMEX_USER")]
+    pub user: Option<String>,
+    #[command(flatten)]
+    pub overrides: Overrides,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+// Settings from the config file that flags and env variables replace
+#[derive(Args)]
+pub struct Overrides {
+    /// Address to listen on [default: 0.0.0.0]
+    #[arg(long, global = true, env = "TABS_MEMEX_ADDRESS")]
+    address: Option<String>,
+    /// [default: 3000]
+    #[arg(long, global = true, env = "TABS_MEMEX_PORT")]
+    port: Option<u16>,
+    /// Directory with templates overriding the built-in ones
+    #[arg(long, global = true, env = "TABS_MEMEX_TEMPLATES")]
+    templates: Option<PathBuf>,
+    /// Bookmarks per page [default: 200]
+    #[arg(long, global = true, env = "TABS_MEMEX_PAGE_SIZE")]
+    page_size: Option<usize>,
+    /// Tag shown on the homepage [default: the imp tag]
+    #[arg(long, global = true, env = "TABS_MEMEX_HOME_TAG")]
+    home_tag: Option<String>,
+    /// Search shown on the homepage instead of the home tag
+    #[arg(long, global = true, env = "TABS_MEMEX_HOME_QUERY")]
+    home_query: Option<String>,
+    /// [default: private]
+    #[arg(long, global = true, env = "TABS_MEMEX_PRIVATE_TAG")]
+    private_tag: Option<String>,
+    /// [default: todo]
+    #[arg(long, global = true, env = "TABS_MEMEX_TODO_TAG")]
+    todo_tag: Option<String>,
+    /// [default: done]
+    #[arg(long, global = true, env = "TABS_MEMEX_DONE_TAG")]
+    done_tag: Option<String>,
+    /// [default: imp]
+    #[arg(long, global = true, env = "TABS_MEMEX_IMP_TAG")]
+    imp_tag: Option<String>,
+}
+
+impl Overrides {
+    pub fn apply(self, config: &mut Config) {
+        config.address = self.address.unwrap_or(config.address.clone());
+        config.port = self.port.unwrap_or(config.port);
+        config.templates = self.templates.or(config.templates.take());
+        config.page_size = self.page_size.unwrap_or(config.page_size);
+        config.home_tag = self.home_tag.or(config.home_tag.take());
+        config.home_query = self.home_query.or(config.home_query.take());
+        let tags = &mut config.tags;
+        tags.private = self.private_tag.unwrap_or(tags.private.clone());
+        tags.todo = self.todo_tag.unwrap_or(tags.todo.clone());
+        tags.done = self.done_tag.unwrap_or(tags.done.clone());
+        tags.imp = self.imp_tag.unwrap_or(tags.imp.clone());
+    }
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the web server (default)
+    Serve,
+    /// Add bookmarks read from stdin
+    Add {
+        /// Tags for all added bookmarks, space-separated
+        #[arg(long, default_value = "")]
+        tags: String,
+        #[arg(long, value_enum, default_value_t = AddFormat::Lines)]
+        format: AddFormat,
+    },
+    /// Search with words, "phrases", #tag, -#tag, site:, after:, before: and is:untagged, or all bookmarks from a date
+    Search {
+        #[arg(required_unless_present = "date")]
+        query: Option<String>,
+        /// YYYY-MM-DD
+        #[arg(long, conflicts_with = "query")]
+        date: Option<String>,
+        #[arg(long, value_enum, default_value_t)]
+        sort: SearchSort,
+    },
+    /// Add or remove tags of a bookmark
+    Tag {
+        id: i64,
+        #[arg(required_unless_present = "remove")]
+        tags: Vec<String>,
+        /// Tag to remove, can be repeated
+        #[arg(long, short)]
+        remove: Vec<String>,
+    },
+    /// Import bookmarks from a file
+    Import {
+        #[arg(value_enum)]
+        format: ImportFormat,
+        file: PathBuf,
+        /// Tags for all imported bookmarks, space-separated
+        #[arg(long, default_value = "")]
+        tags: String,
+    },
+    /// Export all bookmarks to stdout
+    Export {
+        #[arg(value_enum)]
+        format: ExportFormat,
+    },
+    /// Show bookmark and tag counts
+    Stats,
+    /// Create a login or change its password, read from stdin
+    Passwd { name: String },
+    /// Share a tag with other logins, they see each other's bookmarks with it
+    Share {
+        #[arg(required_unless_present = "list")]
+        tag: Option<String>,
+        users: Vec<String>,
+        /// Remove the users from the tag instead
+        #[arg(long, short)]
+        remove: bool,
+        /// List shared tags and their members
+        #[arg(long, conflicts_with = "tag")]
+        list: bool,
+    },
+    /// Manage API tokens
+    Token {
+        #[command(subcommand)]
+        command: TokenCommand,
+    },
+    /// List schema migrations not yet applied to the database
+    Migrations,
+}
+
+#[derive(Subcommand)]
+pub enum TokenCommand {
+    /// Create a token and print it, it can't be shown again
+    Create {
+        /// Login the token acts as
+        user: String,
+        /// Label to recognize the token by
+        name: String,
+        #[arg(long, value_enum, default_value_t = Scope::Read)]
+        scope: Scope,
+    },
+    List,
+    Revoke {
+        id: i64,
+    },
+}
+
+#[derive(Clone, ValueEnum)]
+pub enum AddFormat {
+    /// Name and url lines, as in the web form
+    Lines,
+    /// OneTab export
+    Onetab,
+}
+
+#[derive(Clone, ValueEnum)]
+pub enum ImportFormat {
+    Html,
+    Places,
+    Session,
+    Chrome,
+    Json,
+    Csv,
+}
+
+#[derive(Clone, ValueEnum)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+    /// Netscape bookmark file, tags as folders
+    Html,
+    /// Netscape bookmark file, tags as TAGS attributes
+    HtmlTags,
+}
+
+fn print_bookmarks(bookmarks: &[Bookmark]) {
+    for b in bookmarks {
+        println!(
+            "{}\t{}\t{}\t{}",
+            b.id,
+            b.url,
+            b.name,
+            b.tags.iter().cloned().collect::<Vec<String>>().join(" ")
+        );
+    }
+}
+
+fn user_id(db: &Db, name: &str) -> Result<i64, MyError> {
+    Ok(db
+        .get_user(name)?
+        .ok_or(MyError::NotFound(format!("No user '{name}'")))?
+        .0)
+}
+
+// Without --user the only login is used, or user 0 before there is one
+fn cli_user(db: &Db, name: Option<&str>) -> Result<i64, MyError> {
+    if let Some(name) = name {
+        return user_id(db, name);
+    }
+    match db.list_users()?.as_slice() {
+        [] => Ok(0),
+        [(id, _)] => Ok(*id),
+        _ => Err(MyError::BadRequest(
+            "Several logins exist, choose one with --user".to_string(),
+        )),
+    }
+}
+
+pub fn run(config: &Config, user: Option<&str>, command: Command) -> Result<(), MyError> {
+    if let Command::Migrations = command {
+        for name in Db::open(&config.db).pending_migrations()? {
+            println!("{name}");
+        }
+        return Ok(());
+    }
+
+    let mut db = Db::new(&config.db, config.tags.clone());
+    // Local access to the database file is as good as being logged in,
+    // login management works across all users
+    if !matches!(
+        command,
+        Command::Passwd { .. } | Command::Token { .. } | Command::Share { .. }
+    ) {
+        db.scope(cli_user(&db, user)?, true)?;
+    }
+    match command {
+        Command::Add { tags, format } => {
+            let mut input = String::new();
+            std::io::stdin().read_to_string(&mut input)?;
+            print_bookmarks(&match format {
+                AddFormat::Lines => db.insert(&input, &tags)?,
+                AddFormat::Onetab => db.insert_bookmarks(import::parse_onetab(
+                    &input,
+                    &format!("onetab-{}", import::today()),
+                    &tags,
+                ))?,
+            });
+        }
+        Command::Search { query, date, sort } => print_bookmarks(&match (query, date) {
+            (_, Some(date)) => db.get_bookmarks_by_date(&date, &Page::default())?.0,
+            (Some(query), _) => {
+                db.search(
+                    &SearchQuery {
+                        sort,
+                        ..query.parse()?
+                    },
+                    &Page::default(),
+                    false,
+                )?
+                .0
+            }
+            _ => Vec::new(),
+        }),
+        Command::Tag { id, tags, remove } => {
+            let for_bookmark = |err: rusqlite::Error| MyError::from(err).for_bookmark();
+            let mut bookmark = db.get_bookmark_by_id(id).map_err(for_bookmark)?;
+            for tag in tags
+                .into_iter()
+                .chain(remove.iter().map(|tag| format!("-{tag}")))
+            {
+                bookmark = db.set_tag(&tag, id).map_err(for_bookmark)?;
+            }
+            print_bookmarks(&[bookmark]);
+        }
+        Command::Import { format, file, tags } => {
+            let bookmarks = match format {
+                ImportFormat::Html => {
+                    import::parse_netscape(&std::fs::read_to_string(file)?, &tags)
+                }
+                ImportFormat::Places => import::read_places(&file, &tags)?,
+                ImportFormat::Session => import::parse_session(
+                    &std::fs::read(file)?,
+                    &format!("session-{}", import::today()),
+                    &tags,
+                )?,
+                ImportFormat::Chrome => import::parse_chrome(&std::fs::read(file)?, &tags)?,
+                ImportFormat::Csv => import::parse_csv(&std::fs::read_to_string(file)?, &tags)?,
+                ImportFormat::Json => {
+                    let backup: Backup = serde_json::from_slice(&std::fs::read(file)?)?;
+                    print_bookmarks(&db.import_json(&backup)?);
+                    return Ok(());
+                }
+            };
+            print_bookmarks(&db.insert_bookmarks(bookmarks)?);
+        }
+        Command::Export { format } => match format {
+            ExportFormat::Csv => println!("{}", db.export_csv()?),
+            ExportFormat::Json => println!("{}", serde_json::to_string_pretty(&db.export_json()?)?),
+            ExportFormat::Html | ExportFormat::HtmlTags => println!(
+                "{}",
+                handlers::render_netscape(
+                    &environment(config),
+                    &db.export_json()?.bookmarks,
+                    matches!(format, ExportFormat::HtmlTags),
+                )?
+            ),
+        },
+        Command::Stats => {
+            let tags = db.list_tags()?;
+            println!("bookmarks\t{}", db.count_all()?);
+            println!("tags\t{}", tags.len());
+            println!("favorites\t{}", db.get_favorites()?.len());
+            let mut top: Vec<_> = tags.iter().collect();
+            top.sort_by_key(|tag| std::cmp::Reverse(tag.bookmarks_count));
+            for tag in top.iter().take(10) {
+                println!("#{}\t{}", tag.tag_name, tag.bookmarks_count);
+            }
+        }
+        Command::Passwd { name } => {
+            let mut password = String::new();
+            std::io::stdin().read_line(&mut password)?;
+            let password = password.trim_end_matches(['\r', '\n']);
+            if password.is_empty() {
+                return Err(MyError::BadRequest("Empty password".to_string()));
+            }
+            db.set_password(&name, &auth::hash_password(password)?)?;
+            println!("Password set for {name}");
+        }
+        Command::Share {
+            tag,
+            users,
+            remove,
+            list,
+        } => {
+            if let Some(tag) = tag.filter(|_| !list) {
+                for name in &users {
+                    let id = user_id(&db, name)?;
+                    if remove {
+                        db.unshare_tag(&tag, id)?;
+                    } else {
+                        db.share_tag(&tag, id)?;
+                    }
+                }
+            }
+            for (tag, members) in db.list_shared_tags()? {
+                println!("#{tag}\t{members}");
+            }
+        }
+        Command::Token { command } => match command {
+            TokenCommand::Create { user, name, scope } => {
+                let token = auth::new_token();
+                db.create_token(
+                    &name,
+                    &auth::token_hash(&token),
+                    scope,
+                    user_id(&db, &user)?,
+                )?;
+                println!("{token}");
+            }
+            TokenCommand::List => {
+                for token in db.list_tokens()? {
+                    println!(
+                        "{}\t{}\t{}\t{}",
+                        token.id,
+                        token.name,
+                        token.scope.as_str(),
+                        token.user
+                    );
+                }
+            }
+            TokenCommand::Revoke { id } => {
+                if db.delete_token(id)? == 0 {
+                    return Err(MyError::NotFound(format!("No token {id}")));
+                }
+            }
+        },
+        Command::Serve | Command::Migrations => {}
+    }
+
+    Ok(())
+}
@@ -0,0 +1,53 @@
+use serde::Deserialize;
+
+use std::path::{Path, PathBuf};
+
+use crate::types::{MyError, SpecialTags};
+
+const DEFAULT_PATH: &str = "tabs-memex.toml";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub address: String,
+    pub port: u16,
+    pub db: String,
+    pub templates: Option<PathBuf>,
+    pub page_size: usize,
+    // number of pooled database connections
+    pub pool_size: usize,
+    // tags.imp when not set
+    pub home_tag: Option<String>,
+    // search query shown on the homepage instead of home_tag
+    pub home_query: Option<String>,
+    pub tags: SpecialTags,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            address: "0.0.0.0".to_string(),
+            port: 3000,
+            db: "main.db3".to_string(),
+            templates: None,
+            page_size: 200,
+            pool_size: std::thread::available_parallelism().map_or(4, |n| n.get().max(4)),
+            home_tag: None,
+            home_query: None,
+            tags: SpecialTags::default(),
+        }
+    }
+}
+
+impl Config {
+    // Without an explicit path, tabs-memex.toml is read if it exists
+    pub fn load(path: Option<&Path>) -> Result<Self, MyError> {
+        match path {
+            Some(path) => Ok(toml::from_str(&std::fs::read_to_string(path)?)?),
+            None if Path::new(DEFAULT_PATH).exists() => {
+                Ok(toml::from_str(&std::fs::read_to_string(DEFAULT_PATH)?)?)
+            }
+            None => Ok(Config::default()),
+        }
+    }
+}
@@ -1,109 +1,350 @@
 use rusqlite::{
-    Connection, OpenFlags, Result, config::DbConfig::SQLITE_DBCONFIG_ENABLE_FKEY, params,
+    Connection, OpenFlags, OptionalExtension, Result, TransactionBehavior,
+    config::DbConfig::SQLITE_DBCONFIG_ENABLE_FKEY, params, params_from_iter,
 };
 
-use std::collections::BTreeSet;
+use std::{
+    collections::BTreeSet,
+    ops::{Deref, DerefMut},
+    sync::{Condvar, Mutex, PoisonError},
+    time::Duration,
+};
+
+use crate::{
+    migrations,
+    types::{
+        ApiToken, Backup, Bookmark, Page, PageSort, Scope, SearchQuery, SearchSort, SpecialTags,
+        Tag,
+    },
+};
+
+// RFC 4180: fields with separators, quotes or line breaks are quoted, quotes are doubled
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
 
-use crate::types::{Bookmark, Page, Tag};
+// Names render with `| safe` so search highlights show, they must never hold markup
+fn escape_name(name: &str) -> String {
+    name.replace('<', "&lt").replace('>', "&gt")
+}
+
+// The lowercased host of a url column: what sits between :// and the next /
+fn host(url: &str) -> String {
+    format!(
+        "lower(substr({url}, instr({url}, '://') + 3,
+            instr(substr({url}, instr({url}, '://') + 3) || '/', '/') - 1))"
+    )
+}
 
 pub struct Db {
     conn: Connection,
+    tags: SpecialTags,
+    user_id: i64,
+    show_private: bool,
+}
+
+pub struct Pool {
+    dbs: Mutex<Vec<Db>>,
+    released: Condvar,
+}
+
+pub struct PooledDb<'a> {
+    db: Option<Db>,
+    pool: &'a Pool,
+}
+
+impl Pool {
+    pub fn new(file_path: &str, tags: SpecialTags, size: usize) -> Self {
+        Pool {
+            dbs: Mutex::new(
+                (0..size.max(1))
+                    .map(|_| Db::new(file_path, tags.clone()))
+                    .collect(),
+            ),
+            released: Condvar::new(),
+        }
+    }
+
+    // Blocks until a connection is free
+    pub fn get(&self) -> PooledDb<'_> {
+        let mut dbs = self.dbs.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            if let Some(db) = dbs.pop() {
+                return PooledDb {
+                    db: Some(db),
+                    pool: self,
+                };
+            }
+            dbs = self
+                .released
+                .wait(dbs)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+impl Deref for PooledDb<'_> {
+    type Target = Db;
+
+    fn deref(&self) -> &Db {
+        self.db
+            .as_ref()
+            .expect("connection already returned to pool")
+    }
+}
+
+impl DerefMut for PooledDb<'_> {
+    fn deref_mut(&mut self) -> &mut Db {
+        self.db
+            .as_mut()
+            .expect("connection already returned to pool")
+    }
+}
+
+impl Drop for PooledDb<'_> {
+    fn drop(&mut self) {
+        if let Some(db) = self.db.take() {
+            self.pool
+                .dbs
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(db);
+            self.pool.released.notify_one();
+        }
+    }
 }
 
 impl Db {
-    pub fn new(file_path: &str) -> Self {
-        match Connection::open_with_flags(
+    pub fn open(file_path: &str) -> Self {
+        let conn = Connection::open_with_flags(
             file_path,
-            OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX,
-        ) {
-            Ok(conn) => Db { conn },
-            Err(_) => {
-                let conn = Connection::open(file_path)
-                    .expect("Error while opening connection to new database");
-                conn.set_db_config(SQLITE_DBCONFIG_ENABLE_FKEY, true)
-                    .expect("Error while setting SQLITE_DBCONFIG_ENABLE_FKEY");
-                conn.execute_batch(
-                    &std::fs::read_to_string("init.sql").expect("Couldn't read init.sql"),
-                )
-                .expect("Couldn't execute init.sql");
+            OpenFlags::SQLITE_OPEN_READ_WRITE
+                | OpenFlags::SQLITE_OPEN_CREATE
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )
+        .expect("Error while opening connection to database");
+        conn.set_db_config(SQLITE_DBCONFIG_ENABLE_FKEY, true)
+            .expect("Error while setting SQLITE_DBCONFIG_ENABLE_FKEY");
+        // WAL lets readers run alongside a writer, writers wait on each other
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))
+            .expect("Error while enabling WAL mode");
+        conn.busy_timeout(Duration::from_secs(5))
+            .expect("Error while setting busy timeout");
+
+        Db {
+            conn,
+            tags: SpecialTags::default(),
+            user_id: 0,
+            show_private: false,
+        }
+    }
+
+    pub fn new(file_path: &str, tags: SpecialTags) -> Self {
+        let mut db = Self::open(file_path);
+        migrations::migrate(&mut db.conn).expect("Couldn't apply migrations");
+        db.tags = tags;
+        // Every read goes through visible_bookmarks: the user's own bookmarks and
+        // those of other members of their shared tags, minus bookmarks with a tag
+        // from hidden_tags. Other members' private bookmarks are never shared.
+        // Writes only touch owned_bookmarks. All per connection.
+        db.conn
+            .execute_batch(
+                "CREATE TEMP TABLE scope (user_id INTEGER NOT NULL);
+                 INSERT INTO temp.scope VALUES (0);
+                 CREATE TEMP TABLE hidden_tags (tag_name TEXT NOT NULL);
+                 CREATE TEMP TABLE private_tag (tag_name TEXT NOT NULL);
+                 CREATE TEMP VIEW owned_bookmarks AS
+                     SELECT * FROM bookmarks WHERE user_id = (SELECT user_id FROM temp.scope);
+                 CREATE TEMP VIEW visible_bookmarks AS
+                     SELECT * FROM bookmarks
+                     WHERE (user_id = (SELECT user_id FROM temp.scope)
+                         OR id IN (SELECT tags.bookmark_id FROM tags
+                             JOIN shared_tags AS mine ON mine.tag_name = tags.tag_name
+                                 AND mine.user_id = (SELECT user_id FROM temp.scope)
+                             JOIN shared_tags AS theirs ON theirs.tag_name = tags.tag_name
+                             JOIN bookmarks AS shared ON shared.id = tags.bookmark_id
+                                 AND shared.user_id = theirs.user_id
+                             WHERE tags.bookmark_id NOT IN (SELECT bookmark_id FROM tags
+                                 WHERE tag_name IN temp.private_tag)))
+                         AND id NOT IN
+                             (SELECT bookmark_id FROM tags WHERE tag_name IN temp.hidden_tags);",
+            )
+            .expect("Error while creating visible_bookmarks");
+        db.conn
+            .execute(
+                "INSERT INTO temp.private_tag VALUES (?)",
+                params![db.tags.private],
+            )
+            .expect("Error while creating visible_bookmarks");
+        db.show_private = true;
+        db.scope(0, false)
+            .expect("Error while hiding private bookmarks");
 
-                Db { conn }
+        db
+    }
+
+    // Set for every request from its authentication, see AppState::db.
+    // User 0 owns bookmarks saved before the first login was created.
+    pub fn user_id(&self) -> i64 {
+        self.user_id
+    }
+
+    pub fn scope(&mut self, user_id: i64, show_private: bool) -> Result<()> {
+        if user_id != self.user_id {
+            self.conn
+                .execute("UPDATE temp.scope SET user_id = ?", params![user_id])?;
+            self.user_id = user_id;
+        }
+        if show_private != self.show_private {
+            self.conn.execute("DELETE FROM temp.hidden_tags", [])?;
+            if !show_private {
+                self.conn.execute(
+                    "INSERT INTO temp.hidden_tags VALUES (?)",
+                    params![self.tags.private],
+                )?;
             }
+            self.show_private = show_private;
         }
+
+        Ok(())
     }
 
-    pub fn update_bookmark(&self, new: &Bookmark) -> Result<Bookmark> {
-        self.conn.execute(
+    pub fn pending_migrations(&self) -> Result<Vec<&'static str>> {
+        migrations::pending(&self.conn)
+    }
+
+    // One transaction, so other connections never see the bookmark between
+    // its tags being cleared and written back, when private would be missing
+    pub fn update_bookmark(&mut self, new: &Bookmark) -> Result<Bookmark> {
+        self.bookmark_by_id("owned_bookmarks", new.id)?;
+        let tx = self
+            .conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)?;
+        tx.execute(
             "UPDATE bookmarks
              SET name = ?1, url = ?2, description = ?3
              WHERE id = ?4",
-            params![new.name, new.url, new.description, new.id],
+            params![escape_name(&new.name), new.url, new.description, new.id],
         )?;
 
-        self.conn
-            .execute("DELETE FROM tags WHERE bookmark_id = ?", params![new.id])?;
+        tx.execute("DELETE FROM tags WHERE bookmark_id = ?", params![new.id])?;
 
         for tag in &new.tags {
-            self.conn
-                .execute("INSERT OR IGNORE INTO tags VALUES (?1, ?2)", params![
-                    tag.to_lowercase(),
-                    new.id
-                ])?;
+            tx.execute(
+                "INSERT OR IGNORE INTO tags VALUES (?1, ?2)",
+                params![tag.to_lowercase(), new.id],
+            )?;
         }
+        tx.commit()?;
 
-        self.get_bookmark_by_id(new.id)
+        self.bookmark_by_id("owned_bookmarks", new.id)
     }
 
-    pub fn delete_bookmark(&self, ids: &[i64]) -> Result<Vec<Bookmark>> {
-        let mut res: Vec<Bookmark> = Vec::new();
-        for &id in ids {
-            res.push(self.get_bookmark_by_id(id)?);
+    // Either every bookmark is deleted or, when one isn't owned, none
+    pub fn delete_bookmark(&mut self, ids: &[i64]) -> Result<Vec<Bookmark>> {
+        let res = ids
+            .iter()
+            .map(|&id| self.bookmark_by_id("owned_bookmarks", id))
+            .collect::<Result<Vec<Bookmark>>>()?;
 
-            self.conn
-                .execute("DELETE FROM bookmarks WHERE id = ?", params![id])?;
-            self.conn
-                .execute("DELETE FROM tags WHERE bookmark_id = ?", params![id])?;
+        let tx = self
+            .conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)?;
+        for &id in ids {
+            tx.execute("DELETE FROM bookmarks WHERE id = ?", params![id])?;
+            tx.execute("DELETE FROM tags WHERE bookmark_id = ?", params![id])?;
         }
+        tx.commit()?;
 
-        println!("Deleted: {res:?}");
+        eprintln!("Deleted: {res:?}");
         Ok(res)
     }
 
-    pub fn set_tag(&self, name: &str, id: i64) -> Result<Bookmark> {
+    pub fn set_tag(&mut self, name: &str, id: i64) -> Result<Bookmark> {
+        self.bookmark_by_id("owned_bookmarks", id)?;
+        let tx = self
+            .conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)?;
         if let Some(tag_name) = name.strip_prefix('-') {
-            self.conn.execute(
+            tx.execute(
                 "DELETE FROM tags WHERE tag_name = ?1 AND bookmark_id = ?2",
                 params![tag_name, id],
             )?;
         } else {
-            self.conn
-                .execute("INSERT INTO tags VALUES (?1, ?2)", params![name, id])?;
+            tx.execute("INSERT INTO tags VALUES (?1, ?2)", params![name, id])?;
+            if name == self.tags.done {
+                tx.execute(
+                    "DELETE FROM tags WHERE tag_name = ?1 AND bookmark_id = ?2",
+                    params![self.tags.todo, id],
+                )?;
+            }
         }
+        tx.commit()?;
 
-        self.get_bookmark_by_id(id)
+        self.bookmark_by_id("owned_bookmarks", id)
     }
 
     pub fn delete_tag(&self, name: &str) -> Result<usize> {
-        self.conn
-            .execute("DELETE FROM tags WHERE tag_name = ?", params![name])
+        self.conn.execute(
+            "DELETE FROM tags
+             WHERE tag_name = ? AND bookmark_id IN (SELECT id FROM owned_bookmarks)",
+            params![name],
+        )
+    }
+
+    pub fn tag_exists(&self, name: &str) -> Result<bool> {
+        self.conn.query_row(
+            "SELECT count() > 0 FROM tags
+             WHERE tag_name = ? AND bookmark_id IN (SELECT id FROM visible_bookmarks)",
+            params![name],
+            |row| row.get(0),
+        )
+    }
+
+    // Bookmarks that already have `into` keep it, the merged tags are removed from all owned bookmarks
+    pub fn merge_tags(&mut self, tags: &[String], into: &str) -> Result<usize> {
+        let tx = self
+            .conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let mut merged = 0;
+        for tag in tags.iter().filter(|&tag| tag != into) {
+            merged += tx.execute(
+                "UPDATE OR IGNORE tags SET tag_name = ?1
+                 WHERE tag_name = ?2 AND bookmark_id IN (SELECT id FROM owned_bookmarks)",
+                params![into, tag],
+            )?;
+            tx.execute(
+                "DELETE FROM tags
+                 WHERE tag_name = ? AND bookmark_id IN (SELECT id FROM owned_bookmarks)",
+                params![tag],
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(merged)
     }
 
     pub fn rename_tag(&self, old: &str, new: &str) -> Result<usize> {
         self.conn.execute(
-            "UPDATE tags SET tag_name = ?1 WHERE tag_name = ?2",
+            "UPDATE tags SET tag_name = ?1
+             WHERE tag_name = ?2 AND bookmark_id IN (SELECT id FROM owned_bookmarks)",
             params![new, old],
         )
     }
 
     pub fn set_favorite(&self, path: &str) -> Result<i64> {
         self.conn
-            .prepare("INSERT INTO favorites VALUES (?)")?
+            .prepare("INSERT INTO favorites VALUES ((SELECT user_id FROM temp.scope), ?)")?
             .insert(params![path])
     }
 
     pub fn get_favorites(&self) -> Result<Vec<String>> {
         self.conn
-            .prepare("SELECT * FROM favorites")?
+            .prepare("SELECT * FROM favorites WHERE user_id = (SELECT user_id FROM temp.scope)")?
             .query_map([], |row| row.get("path"))?
             .collect()
     }
@@ -112,8 +353,7 @@ impl Db {
         self.conn
             .prepare(
                 "SELECT tag_name, count(bookmark_id) AS bookmarks_count
-                 FROM tags WHERE bookmark_id NOT IN
-                     (SELECT DISTINCT bookmark_id FROM tags WHERE tag_name = 'private')
+                 FROM tags WHERE bookmark_id IN (SELECT id FROM visible_bookmarks)
                  GROUP BY tag_name",
             )?
             .query_map([], |row| {
@@ -127,23 +367,29 @@ impl Db {
 
     #[allow(clippy::let_and_return)]
     pub fn get_page(&self, page: &Page) -> Result<Vec<Bookmark>> {
-        let offset = page.p.unwrap_or(0);
-        let limit = page.limit.unwrap_or(200);
-        let sort = page
-            .sort
-            .clone()
-            .unwrap_or("creation_time DESC".to_string());
+        let (limit, offset) = page.bounds(Some(200));
+        let sort = match page.sort.unwrap_or_default() {
+            PageSort::Newest => "creation_time DESC, id DESC".to_string(),
+            PageSort::Oldest => "creation_time ASC, id ASC".to_string(),
+            PageSort::Name => "name COLLATE NOCASE ASC, id ASC".to_string(),
+            PageSort::Domain => format!("{}, url, id", host("url")),
+            PageSort::Tags => {
+                "(SELECT count() FROM tags WHERE tags.bookmark_id = id) DESC, creation_time DESC, id DESC"
+                    .to_string()
+            }
+            PageSort::Visited => {
+                "last_visited IS NULL, last_visited DESC, creation_time DESC, id DESC".to_string()
+            }
+        };
         let mut stmt = self.conn.prepare(&format!(
-            "SELECT * FROM bookmarks LEFT JOIN
+            "SELECT * FROM visible_bookmarks LEFT JOIN
                 (SELECT group_concat(tag_name) AS tags, bookmark_id FROM tags GROUP BY bookmark_id)
             ON id = bookmark_id
-            WHERE id NOT IN
-                (SELECT DISTINCT bookmark_id FROM tags WHERE tag_name = 'private')
             ORDER BY {sort}
             LIMIT ?1 OFFSET ?2"
         ))?;
         let res = stmt
-            .query_map(params![limit, limit * offset], |row| {
+            .query_map(params![limit, offset], |row| {
                 Ok(Bookmark {
                     id: row.get("id")?,
                     name: row.get("name")?,
@@ -160,11 +406,26 @@ impl Db {
         res
     }
 
+    pub fn visit(&self, id: i64) -> Result<usize> {
+        self.conn.execute(
+            "UPDATE bookmarks SET last_visited = unixepoch()
+             WHERE id = ?1 AND id IN (SELECT id FROM owned_bookmarks)",
+            params![id],
+        )
+    }
+
     pub fn get_bookmark_by_id(&self, id: i64) -> Result<Bookmark> {
+        self.bookmark_by_id("visible_bookmarks", id)
+    }
+
+    // Writes report back the bookmark they touched even when it's hidden
+    fn bookmark_by_id(&self, from: &str, id: i64) -> Result<Bookmark> {
         self.conn.query_row(
-            "SELECT * FROM bookmarks LEFT JOIN 
-                (SELECT group_concat(tag_name) AS tags, bookmark_id FROM tags WHERE bookmark_id = ?1)
-            ON id = bookmark_id WHERE id = ?1",
+            &format!(
+                "SELECT * FROM {from} LEFT JOIN
+                    (SELECT group_concat(tag_name) AS tags, bookmark_id FROM tags WHERE bookmark_id = ?1)
+                ON id = bookmark_id WHERE id = ?1"
+            ),
             params![id],
             |row| {
                 Ok(Bookmark {
@@ -181,9 +442,10 @@ impl Db {
         )
     }
 
-    pub fn get_bookmark_by_url(&self, url: &str) -> Result<Bookmark> {
+    // Only for reporting duplicates in insert_bookmarks
+    fn get_bookmark_by_url(&self, url: &str) -> Result<Bookmark> {
         self.conn.query_row(
-            "SELECT * FROM bookmarks LEFT JOIN
+            "SELECT * FROM owned_bookmarks LEFT JOIN
                 (SELECT group_concat(tag_name) as tags, bookmark_id FROM tags GROUP BY bookmark_id)
             ON id = bookmark_id WHERE url = ?",
             params![url],
@@ -202,47 +464,27 @@ impl Db {
         )
     }
 
-    #[allow(clippy::let_and_return)]
-    pub fn get_bookmarks_by_tag(&self, tag_name: &str) -> Result<Vec<Bookmark>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT * FROM bookmarks
-            JOIN (SELECT group_concat(tag_name) AS tags, bookmark_id FROM tags
-                  WHERE bookmark_id IN (SELECT bookmark_id FROM tags WHERE tag_name = ?)
-                      AND bookmark_id NOT IN (SELECT bookmark_id FROM tags WHERE tag_name = 'private')
-                  GROUP BY bookmark_id)
-            ON id = bookmark_id
-            ORDER BY creation_time DESC",
-        )?;
-        let res = stmt
-            .query_map(params![tag_name], |row| {
-                Ok(Bookmark {
-                    id: row.get("id")?,
-                    name: row.get("name")?,
-                    url: row.get("url")?,
-                    creation_time: row.get("creation_time")?,
-                    description: row.get("description")?,
-                    tags: row
-                        .get("tags")
-                        .map(|s: String| s.split(',').map(String::from).collect())?,
-                })
-            })?
-            .collect();
-
-        res
-    }
-
-    #[allow(clippy::let_and_return)]
-    pub fn get_bookmarks_by_date(&self, date: &str) -> Result<Vec<Bookmark>> {
-        let mut stmt = self.conn.prepare(
-            "SELECT * FROM bookmarks LEFT JOIN
-                (SELECT group_concat(tag_name) as tags, bookmark_id FROM tags GROUP BY bookmark_id)
-            ON id = bookmark_id
-            WHERE date(creation_time, 'unixepoch', 'localtime') = ?
-                AND tags NOT LIKE '%private%'
-            ORDER BY creation_time DESC",
+    // One page of a listing and the total it was cut from. `from` is everything
+    // between the selected columns and ORDER BY, a page without a limit is everything.
+    fn paged(
+        &self,
+        columns: &str,
+        from: &str,
+        order: &str,
+        values: &[String],
+        page: &Page,
+    ) -> Result<(Vec<Bookmark>, usize)> {
+        let total = self.conn.query_row(
+            &format!("SELECT count() {from}"),
+            params_from_iter(values),
+            |row| row.get(0),
         )?;
-        let res = stmt
-            .query_map(params![date], |row| {
+        let (limit, offset) = page.bounds(None);
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {columns} {from} ORDER BY {order} LIMIT {limit} OFFSET {offset}"
+        ))?;
+        let bookmarks = stmt
+            .query_map(params_from_iter(values), |row| {
                 Ok(Bookmark {
                     id: row.get("id")?,
                     name: row.get("name")?,
@@ -254,113 +496,160 @@ impl Db {
                     }),
                 })
             })?
-            .collect();
+            .collect::<Result<_>>()?;
 
-        res
+        Ok((bookmarks, total))
     }
 
-    pub fn search(&self, query: &str) -> Result<Vec<Bookmark>> {
-        if query.starts_with("# ") {
-            let mut stmt = self.conn.prepare(
-                &format!(
-                    "SELECT * FROM bookmarks LEFT JOIN
-                        (SELECT group_concat(tag_name) as tags, bookmark_id FROM tags GROUP BY bookmark_id)
-                    ON id = bookmark_id
-                    WHERE id IN (
-                    {}
-                    ) ORDER BY creation_time DESC",
-                    query
-                        .split(' ')
-                        .skip(1)
-                        .map(|s| format!("SELECT bookmark_id FROM tags WHERE tag_name = '{}'", s))
-                        .collect::<Vec<String>>()
-                        .join(" INTERSECT ")
-                )
-            )?;
+    pub fn get_bookmarks_by_tag(
+        &self,
+        tag_name: &str,
+        page: &Page,
+    ) -> Result<(Vec<Bookmark>, usize)> {
+        self.paged(
+            "*",
+            "FROM visible_bookmarks
+            JOIN (SELECT group_concat(tag_name) AS tags, bookmark_id FROM tags
+                  WHERE bookmark_id IN (SELECT bookmark_id FROM tags WHERE tag_name = ?1)
+                  GROUP BY bookmark_id)
+            ON id = bookmark_id",
+            "creation_time DESC, id DESC",
+            &[tag_name.to_string()],
+            page,
+        )
+    }
 
-            let res = stmt
-                .query_map(params![], |row| {
-                    Ok(Bookmark {
-                        id: row.get("id")?,
-                        name: row.get("name")?,
-                        url: row.get("url")?,
-                        creation_time: row.get("creation_time")?,
-                        description: row.get("description")?,
-                        tags: row.get("tags").map_or(BTreeSet::new(), |x: String| {
-                            x.split(',').map(String::from).collect()
-                        }),
-                    })
-                })?
-                .collect::<Result<Vec<Bookmark>>>();
+    pub fn get_bookmarks_by_date(&self, date: &str, page: &Page) -> Result<(Vec<Bookmark>, usize)> {
+        self.paged(
+            "*",
+            "FROM visible_bookmarks LEFT JOIN
+                (SELECT group_concat(tag_name) as tags, bookmark_id FROM tags GROUP BY bookmark_id)
+            ON id = bookmark_id
+            WHERE date(creation_time, 'unixepoch', 'localtime') = ?1",
+            "creation_time DESC, id DESC",
+            &[date.to_string()],
+            page,
+        )
+    }
 
-            return res;
+    // Compiles the query into one statement, every value is bound.
+    // Only pages render highlights, other outputs ask for plain text
+    pub fn search(
+        &self,
+        query: &SearchQuery,
+        page: &Page,
+        highlight: bool,
+    ) -> Result<(Vec<Bookmark>, usize)> {
+        if query.is_empty() {
+            return Ok((Vec::new(), 0));
         }
+        let mut conditions = vec!["1".to_string()];
+        let mut values: Vec<String> = Vec::new();
+        let mut bind = |value: &str| {
+            values.push(value.to_string());
+            format!("?{}", values.len())
+        };
 
-        let mut stmt = self.conn.prepare(
-            "SELECT rowid, highlight(bookmarks_fts, 0, '<mark>', '</mark>') name, url, creation_time,
-                highlight(bookmarks_fts, 3, '<mark>', '</mark>') description, tags
-            FROM bookmarks_fts LEFT JOIN
-                (SELECT group_concat(tag_name) as tags, bookmark_id FROM tags GROUP BY bookmark_id)
-            ON rowid = bookmark_id
-            WHERE bookmarks_fts MATCH ?
-            ORDER BY creation_time DESC",
-        )?;
-        // bm25(bookmarks_fts)
-
-        let mut res: Vec<Bookmark> = stmt
-            .query_map(params![query], |row| {
-                Ok(Bookmark {
-                    id: row.get("rowid")?,
-                    name: row.get("name")?,
-                    url: row.get("url")?,
-                    creation_time: row.get("creation_time")?,
-                    description: row.get("description")?,
-                    tags: row.get("tags").map_or(BTreeSet::new(), |x: String| {
-                        x.split(',').map(String::from).collect()
-                    }),
+        let fts = match query.fts_query() {
+            Some(fts_query) => format!(
+                "JOIN bookmarks_fts ON bookmarks_fts.rowid = b.id AND bookmarks_fts MATCH {}",
+                bind(&fts_query)
+            ),
+            None => String::new(),
+        };
+        let (name, description) = if highlight && !fts.is_empty() {
+            (
+                "highlight(bookmarks_fts, 0, '<mark>', '</mark>')",
+                "highlight(bookmarks_fts, 3, '<mark>', '</mark>')",
+            )
+        } else {
+            ("b.name", "b.description")
+        };
+        let order = match query.sort {
+            // bm25 weights follow the column order: name, url, creation_time, description
+            SearchSort::Relevance if !fts.is_empty() => {
+                "bm25(bookmarks_fts, 10.0, 1.0, 0.0, 4.0), b.creation_time DESC, b.id DESC"
+            }
+            SearchSort::Relevance | SearchSort::Newest => "b.creation_time DESC, b.id DESC",
+            SearchSort::Oldest => "b.creation_time ASC, b.id ASC",
+            SearchSort::Name => "b.name COLLATE NOCASE ASC, b.id ASC",
+        };
+        for term in query.short_terms() {
+            let term = bind(
+                &term
+                    .replace('\\', "\\\\")
+                    .replace('%', "\\%")
+                    .replace('_', "\\_"),
+            );
+            conditions.push(format!(
+                "(b.name LIKE '%' || {term} || '%' ESCAPE '\\'
+                    OR b.url LIKE '%' || {term} || '%' ESCAPE '\\'
+                    OR b.description LIKE '%' || {term} || '%' ESCAPE '\\')"
+            ));
+        }
+        for tag in &query.tags {
+            conditions.push(format!(
+                "b.id IN (SELECT bookmark_id FROM tags WHERE tag_name = {})",
+                bind(tag)
+            ));
+        }
+        for tag in &query.not_tags {
+            conditions.push(format!(
+                "b.id NOT IN (SELECT bookmark_id FROM tags WHERE tag_name = {})",
+                bind(tag)
+            ));
+        }
+        if !query.sites.is_empty() {
+            // Subdomains match too
+            let host = host("b.url");
+            let sites: Vec<String> = query
+                .sites
+                .iter()
+                .map(|site| {
+                    let site = bind(site);
+                    format!(
+                        "({host} = {site} OR substr({host}, -length({site}) - 1) = '.' || {site})"
+                    )
                 })
-            })?
-            .collect::<Result<Vec<Bookmark>>>()
-            .unwrap_or(Vec::new());
-
-        if res.is_empty() {
-            stmt = self.conn.prepare(
-                "SELECT * FROM bookmarks LEFT JOIN
-                    (SELECT group_concat(tag_name) as tags, bookmark_id FROM tags GROUP BY bookmark_id)
-                ON id = bookmark_id
-                WHERE url LIKE '%' || ?1 || '%'
-                    OR name LIKE '%' || ?1 || '%'
-                    OR description LIKE '%' || ?1 || '%'
-                ORDER BY creation_time DESC",
-            )?;
-
-            res = stmt
-                .query_map(params![query], |row| {
-                    Ok(Bookmark {
-                        id: row.get("id")?,
-                        name: row.get("name")?,
-                        url: row.get("url")?,
-                        creation_time: row.get("creation_time")?,
-                        description: row.get("description")?,
-                        tags: row.get("tags").map_or(BTreeSet::new(), |x: String| {
-                            x.split(',').map(String::from).collect()
-                        }),
-                    })
-                })?
-                .collect::<Result<Vec<Bookmark>>>()
-                .unwrap_or(Vec::new());
+                .collect();
+            conditions.push(format!("({})", sites.join(" OR ")));
+        }
+        if let Some(after) = &query.after {
+            conditions.push(format!(
+                "date(b.creation_time, 'unixepoch', 'localtime') >= {}",
+                bind(after)
+            ));
+        }
+        if let Some(before) = &query.before {
+            conditions.push(format!(
+                "date(b.creation_time, 'unixepoch', 'localtime') < {}",
+                bind(before)
+            ));
+        }
+        if query.untagged {
+            conditions.push("b.id NOT IN (SELECT bookmark_id FROM tags)".to_string());
         }
 
-        Ok(res)
+        self.paged(
+            &format!("b.id, {name} name, b.url, b.creation_time, {description} description, tags"),
+            &format!(
+                "FROM visible_bookmarks b {fts} LEFT JOIN
+                    (SELECT group_concat(tag_name) as tags, bookmark_id FROM tags GROUP BY bookmark_id)
+                ON b.id = bookmark_id
+                WHERE {}",
+                conditions.join(" AND ")
+            ),
+            order,
+            &values,
+            page,
+        )
     }
 
     pub fn count_all(&self) -> Result<usize> {
-        self.conn.query_row_and_then(
-            "SELECT count() FROM bookmarks WHERE id NOT IN
-                (SELECT bookmark_id FROM tags WHERE tag_name = 'private')",
-            [],
-            |row| row.get(0),
-        )
+        self.conn
+            .query_row_and_then("SELECT count() FROM visible_bookmarks", [], |row| {
+                row.get(0)
+            })
     }
 
     fn parse_bookmark(entry: [&str; 2], tags_for_all: &str) -> Bookmark {
@@ -394,27 +683,68 @@ impl Db {
     }
 
     pub fn insert(&mut self, input: &str, tags_for_all: &str) -> Result<Vec<Bookmark>> {
-        let tx = self.conn.transaction()?;
-        let mut existing: Vec<String> = Vec::new();
-        let mut not_existing: Vec<i64> = Vec::new();
-
         let bookmarks: Vec<Bookmark> = input
             .lines()
             .array_chunks()
             .map(|entry| Self::parse_bookmark(entry, tags_for_all))
             .collect();
 
+        self.insert_bookmarks(bookmarks)
+    }
+
+    // Unlike insert_bookmarks an existing url is an error
+    pub fn insert_bookmark(&mut self, new: &Bookmark) -> Result<Bookmark> {
+        let tx = self
+            .conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)?;
+        tx.execute(
+            "INSERT INTO bookmarks (user_id, name, url, creation_time, description)
+             VALUES ((SELECT user_id FROM temp.scope), ?1, ?2, coalesce(nullif(?3, 0), unixepoch()), ?4)",
+            params![
+                escape_name(&new.name),
+                new.url,
+                new.creation_time,
+                new.description
+            ],
+        )?;
+        let id = tx.last_insert_rowid();
+        for tag in &new.tags {
+            tx.execute(
+                "INSERT OR IGNORE INTO tags VALUES (?1, ?2)",
+                params![tag.to_lowercase(), id],
+            )?;
+        }
+        tx.commit()?;
+
+        self.bookmark_by_id("owned_bookmarks", id)
+    }
+
+    // creation_time == 0 means "now", existing urls only get new tags and are marked as dup
+    pub fn insert_bookmarks(&mut self, bookmarks: Vec<Bookmark>) -> Result<Vec<Bookmark>> {
+        let tx = self
+            .conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let mut existing: Vec<String> = Vec::new();
+        let mut not_existing: Vec<i64> = Vec::new();
+
         for new in bookmarks {
             if let Err(err) = tx.execute(
-                "INSERT INTO bookmarks (name, url, creation_time, description) VALUES (?1, ?2, unixepoch(), '')",
-                params![new.name.replace('<', "&lt").replace('>', "&gt"), new.url]
+                "INSERT INTO bookmarks (user_id, name, url, creation_time, description)
+                 VALUES ((SELECT user_id FROM temp.scope), ?1, ?2, coalesce(nullif(?3, 0), unixepoch()), ?4)",
+                params![
+                    escape_name(&new.name),
+                    new.url,
+                    new.creation_time,
+                    new.description
+                ],
             ) {
-                println!("{}: {}", err, new.url);
+                eprintln!("{}: {}", err, new.url);
 
                 for tag_name in new.tags {
                     tx.execute(
-                        "INSERT OR IGNORE INTO tags SELECT ?1, id FROM bookmarks WHERE url = ?2",
-                        params![tag_name.to_lowercase(), new.url])?;
+                        "INSERT OR IGNORE INTO tags SELECT ?1, id FROM owned_bookmarks WHERE url = ?2",
+                        params![tag_name.to_lowercase(), new.url],
+                    )?;
                 }
 
                 existing.push(new.url);
@@ -427,7 +757,7 @@ impl Db {
                     )?;
                 }
 
-                println!("Inserted: {}", new.url);
+                eprintln!("Inserted: {}", new.url);
                 not_existing.push(bookmark_id);
             }
         }
@@ -442,13 +772,17 @@ impl Db {
                 b.tags.insert("dup".to_string());
                 Ok(b)
             })
-            .chain(not_existing.into_iter().map(|x| self.get_bookmark_by_id(x)))
+            .chain(
+                not_existing
+                    .into_iter()
+                    .map(|x| self.bookmark_by_id("owned_bookmarks", x)),
+            )
             .collect()
     }
 
     pub fn export_csv(&self) -> Result<String> {
         let mut stmt = self.conn.prepare(
-            "SELECT * FROM bookmarks LEFT JOIN
+            "SELECT * FROM visible_bookmarks LEFT JOIN
                 (SELECT group_concat(tag_name) as tags, bookmark_id FROM tags GROUP BY bookmark_id)
             ON id = bookmark_id",
         )?;
@@ -458,26 +792,508 @@ impl Db {
             &stmt
                 .query_map([], |row| {
                     Ok([
-                        &row.get("name")?,
-                        &format!("\"{}\"", row.get::<&str, String>("description")?),
-                        "",
-                        &row.get("url")?,
-                        &row.get("tags").unwrap_or("".to_string()),
-                        &row.get("creation_time")?,
-                        "",
-                        "",
+                        row.get("name")?,
+                        row.get("description")?,
+                        String::new(),
+                        row.get("url")?,
+                        row.get("tags").unwrap_or_default(),
+                        row.get::<&str, i64>("creation_time")?.to_string(),
+                        String::new(),
+                        String::new(),
                     ]
+                    .map(|field| csv_field(&field))
                     .join(","))
                 })?
                 .collect::<Result<Vec<String>>>()?,
         );
 
-        Ok(data.join("\n"))
+        Ok(data.join("\r\n"))
+    }
+
+    pub fn export_json(&self) -> Result<Backup> {
+        let mut stmt = self.conn.prepare(
+            "SELECT * FROM visible_bookmarks LEFT JOIN
+                (SELECT group_concat(tag_name) as tags, bookmark_id FROM tags GROUP BY bookmark_id)
+            ON id = bookmark_id
+            ORDER BY id",
+        )?;
+        let bookmarks = stmt
+            .query_map([], |row| {
+                Ok(Bookmark {
+                    id: row.get("id")?,
+                    name: row.get("name")?,
+                    url: row.get("url")?,
+                    creation_time: row.get("creation_time")?,
+                    description: row.get("description")?,
+                    tags: row.get("tags").map_or(BTreeSet::new(), |x: String| {
+                        x.split(',').map(String::from).collect()
+                    }),
+                })
+            })?
+            .collect::<Result<Vec<Bookmark>>>()?;
+
+        Ok(Backup {
+            bookmarks,
+            favorites: self.get_favorites()?,
+        })
+    }
+
+    // Keeps ids when they are free, existing urls only get the tags and are marked as dup
+    pub fn import_json(&mut self, backup: &Backup) -> Result<Vec<Bookmark>> {
+        let tx = self
+            .conn
+            .transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let mut existing: Vec<i64> = Vec::new();
+        let mut not_existing: Vec<i64> = Vec::new();
+
+        for b in &backup.bookmarks {
+            let bookmark_id =
+                match tx
+                    .query_row(
+                        "SELECT id FROM owned_bookmarks WHERE url = ?",
+                        params![b.url],
+                        |row| row.get(0),
+                    )
+                    .optional()?
+                {
+                    Some(id) => {
+                        existing.push(id);
+                        id
+                    }
+                    None => {
+                        tx.execute(
+                        "INSERT INTO bookmarks (id, user_id, name, url, creation_time, description)
+                         VALUES ((SELECT iif(count() = 0, ?1, NULL) FROM bookmarks WHERE id = ?1),
+                             (SELECT user_id FROM temp.scope), ?2, ?3, ?4, ?5)",
+                        params![b.id, escape_name(&b.name), b.url, b.creation_time, b.description],
+                    )?;
+                        let id = tx.last_insert_rowid();
+                        not_existing.push(id);
+                        id
+                    }
+                };
+
+            for tag_name in &b.tags {
+                tx.execute(
+                    "INSERT OR IGNORE INTO tags (tag_name, bookmark_id) VALUES (?1, ?2)",
+                    params![tag_name, bookmark_id],
+                )?;
+            }
+        }
+
+        for path in &backup.favorites {
+            tx.execute(
+                "INSERT INTO favorites VALUES ((SELECT user_id FROM temp.scope), ?)",
+                params![path],
+            )?;
+        }
+
+        tx.commit()?;
+
+        existing
+            .into_iter()
+            .map(|x| -> Result<Bookmark> {
+                let mut b = self.bookmark_by_id("owned_bookmarks", x)?;
+                b.tags.insert("dup".to_string());
+                Ok(b)
+            })
+            .chain(
+                not_existing
+                    .into_iter()
+                    .map(|x| self.bookmark_by_id("owned_bookmarks", x)),
+            )
+            .collect()
+    }
+
+    pub fn user_count(&self) -> Result<usize> {
+        self.conn
+            .query_row("SELECT count() FROM users", [], |row| row.get(0))
+    }
+
+    // Whose public bookmarks anonymous visitors see: the only login, or
+    // nobody's once there are several
+    pub fn default_user(&self) -> Result<i64> {
+        self.conn.query_row(
+            "SELECT CASE count() WHEN 1 THEN max(id) ELSE 0 END FROM users",
+            [],
+            |row| row.get(0),
+        )
+    }
+
+    // Creates the user or replaces its password. The first user adopts
+    // everything saved before there were any.
+    pub fn set_password(&self, name: &str, password_hash: &str) -> Result<i64> {
+        let id = self.conn.query_row(
+            "INSERT INTO users (name, password_hash) VALUES (?1, ?2)
+             ON CONFLICT (name) DO UPDATE SET password_hash = excluded.password_hash
+             RETURNING id",
+            params![name, password_hash],
+            |row| row.get(0),
+        )?;
+        if self.user_count()? == 1 {
+            self.conn.execute(
+                "UPDATE bookmarks SET user_id = ? WHERE user_id = 0",
+                params![id],
+            )?;
+            self.conn.execute(
+                "UPDATE favorites SET user_id = ? WHERE user_id = 0",
+                params![id],
+            )?;
+        }
+
+        Ok(id)
+    }
+
+    pub fn list_users(&self) -> Result<Vec<(i64, String)>> {
+        self.conn
+            .prepare("SELECT id, name FROM users ORDER BY id")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect()
+    }
+
+    pub fn share_tag(&self, tag_name: &str, user_id: i64) -> Result<usize> {
+        self.conn.execute(
+            "INSERT INTO shared_tags VALUES (?1, ?2)",
+            params![tag_name.to_lowercase(), user_id],
+        )
+    }
+
+    pub fn unshare_tag(&self, tag_name: &str, user_id: i64) -> Result<usize> {
+        self.conn.execute(
+            "DELETE FROM shared_tags WHERE tag_name = ?1 AND user_id = ?2",
+            params![tag_name, user_id],
+        )
+    }
+
+    // (tag_name, member names)
+    pub fn list_shared_tags(&self) -> Result<Vec<(String, String)>> {
+        self.conn
+            .prepare(
+                "SELECT tag_name, group_concat(name, ' ') FROM shared_tags
+                 JOIN users ON users.id = user_id
+                 GROUP BY tag_name ORDER BY tag_name",
+            )?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect()
+    }
+
+    // (id, password_hash)
+    pub fn get_user(&self, name: &str) -> Result<Option<(i64, String)>> {
+        self.conn
+            .query_row(
+                "SELECT id, password_hash FROM users WHERE name = ?",
+                params![name],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+    }
+
+    // Expired sessions are cleaned up whenever someone logs in
+    pub fn create_session(&self, token_hash: &str, user_id: i64, max_age: i64) -> Result<()> {
+        self.conn
+            .execute("DELETE FROM sessions WHERE expires <= unixepoch()", [])?;
+        self.conn.execute(
+            "INSERT INTO sessions VALUES (?1, ?2, unixepoch() + ?3)",
+            params![token_hash, user_id, max_age],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn get_session(&self, token_hash: &str) -> Result<Option<i64>> {
+        self.conn
+            .query_row(
+                "SELECT user_id FROM sessions WHERE token_hash = ? AND expires > unixepoch()",
+                params![token_hash],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    pub fn delete_session(&self, token_hash: &str) -> Result<usize> {
+        self.conn.execute(
+            "DELETE FROM sessions WHERE token_hash = ?",
+            params![token_hash],
+        )
+    }
+
+    pub fn create_token(
+        &self,
+        name: &str,
+        token_hash: &str,
+        scope: Scope,
+        user_id: i64,
+    ) -> Result<i64> {
+        self.conn
+            .prepare(
+                "INSERT INTO api_tokens (name, token_hash, scope, user_id) VALUES (?1, ?2, ?3, ?4)",
+            )?
+            .insert(params![name, token_hash, scope.as_str(), user_id])
+    }
+
+    // (user_id, scope)
+    pub fn get_token(&self, token_hash: &str) -> Result<Option<(i64, Scope)>> {
+        self.conn
+            .query_row(
+                "SELECT user_id, scope FROM api_tokens WHERE token_hash = ?",
+                params![token_hash],
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        match row.get::<_, String>(1)?.as_str() {
+                            "write" => Scope::Write,
+                            _ => Scope::Read,
+                        },
+                    ))
+                },
+            )
+            .optional()
+    }
+
+    pub fn list_tokens(&self) -> Result<Vec<ApiToken>> {
+        self.conn
+            .prepare(
+                "SELECT api_tokens.id, api_tokens.name, scope, users.name AS user, creation_time
+                 FROM api_tokens JOIN users ON users.id = user_id
+                 ORDER BY api_tokens.id",
+            )?
+            .query_map([], |row| {
+                Ok(ApiToken {
+                    id: row.get("id")?,
+                    name: row.get("name")?,
+                    scope: match row.get::<_, String>("scope")?.as_str() {
+                        "write" => Scope::Write,
+                        _ => Scope::Read,
+                    },
+                    user: row.get("user")?,
+                    creation_time: row.get("creation_time")?,
+                })
+            })?
+            .collect()
+    }
+
+    pub fn delete_token(&self, id: i64) -> Result<usize> {
+        self.conn
+            .execute("DELETE FROM api_tokens WHERE id = ?", params![id])
     }
 }
 
 impl Default for Db {
     fn default() -> Self {
-        Self::new("./main.db3")
+        Self::new("./main.db3", SpecialTags::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn db_with(bookmarks: &[(&str, &[&str])]) -> Db {
+        let mut db = Db::new(":memory:", SpecialTags::default());
+        for (url, tags) in bookmarks {
+            db.insert_bookmark(&Bookmark {
+                name: url.to_string(),
+                url: url.to_string(),
+                tags: tags.iter().map(|tag| tag.to_string()).collect(),
+                ..Default::default()
+            })
+            .unwrap();
+        }
+        db
+    }
+
+    fn urls(db: &Db, query: &str) -> Vec<String> {
+        let mut urls: Vec<String> = db
+            .search(&query.parse().unwrap(), &Page::default(), false)
+            .unwrap()
+            .0
+            .into_iter()
+            .map(|bookmark| bookmark.url)
+            .collect();
+        urls.sort();
+        urls
+    }
+
+    #[test]
+    fn tag_search_binds_quotes() {
+        let db = db_with(&[("a", &["it's"]), ("b", &["rust"])]);
+        assert_eq!(urls(&db, "# it's"), ["a"]);
+        assert!(urls(&db, "# x' OR '1'='1").is_empty());
+        assert!(urls(&db, "# rust' --").is_empty());
+    }
+
+    #[test]
+    fn tag_search_unicode() {
+        let db = db_with(&[
+            ("a", &["café"]),
+            ("b", &["日本語", "café"]),
+            ("c", &["cafe"]),
+        ]);
+        assert_eq!(urls(&db, "# café"), ["a", "b"]);
+        assert_eq!(urls(&db, "# 日本語 café"), ["b"]);
+    }
+
+    #[test]
+    fn tag_search_ignores_case() {
+        let db = db_with(&[("a", &["Todo", "rust"]), ("b", &["todo", "done"])]);
+        assert_eq!(urls(&db, "#TODO"), ["a", "b"]);
+        assert_eq!(urls(&db, "#todo -#DONE"), ["a"]);
+        assert_eq!(urls(&db, "# RUST Todo"), ["a"]);
+    }
+
+    #[test]
+    fn tag_search_intersects_and_ignores_repeats() {
+        let db = db_with(&[("a", &["rust", "web"]), ("b", &["rust"]), ("c", &["web"])]);
+        assert_eq!(urls(&db, "# rust"), ["a", "b"]);
+        assert_eq!(urls(&db, "# rust rust"), ["a", "b"]);
+        assert_eq!(urls(&db, "# rust web rust"), ["a"]);
+    }
+
+    #[test]
+    fn tag_search_skips_empty_tokens() {
+        let db = db_with(&[("a", &["rust", "web"]), ("b", &["rust"])]);
+        assert_eq!(urls(&db, "#   rust    web  "), ["a"]);
+        assert_eq!(urls(&db, "# rust\tweb"), ["a"]);
+    }
+
+    #[test]
+    fn short_terms_match_substrings_literally() {
+        let db = db_with(&[
+            ("https://golang.org", &[]),
+            ("https://rust-lang.org", &[]),
+            ("https://example.com/100%", &[]),
+            ("https://example.com/a_b", &[]),
+        ]);
+        assert_eq!(urls(&db, "go"), ["https://golang.org"]);
+        assert_eq!(urls(&db, "go lang"), ["https://golang.org"]);
+        assert_eq!(urls(&db, "0%"), ["https://example.com/100%"]);
+        assert_eq!(urls(&db, "%"), ["https://example.com/100%"]);
+        assert_eq!(urls(&db, "_"), ["https://example.com/a_b"]);
+        assert!(urls(&db, "\\").is_empty());
+    }
+
+    #[test]
+    fn empty_search_returns_nothing() {
+        let db = db_with(&[("a", &["rust"])]);
+        for query in ["", "   ", "#", "# ", "#    "] {
+            assert!(urls(&db, query).is_empty(), "{query:?}");
+        }
+    }
+
+    #[test]
+    fn search_highlights_only_when_asked() {
+        let db = db_with(&[("rustacean", &[])]);
+        let names = |highlight| {
+            db.search(&"rustacean".parse().unwrap(), &Page::default(), highlight)
+                .unwrap()
+                .0
+                .into_iter()
+                .map(|bookmark| bookmark.name)
+                .collect::<Vec<String>>()
+        };
+        assert_eq!(names(true), ["<mark>rustacean</mark>"]);
+        assert_eq!(names(false), ["rustacean"]);
+    }
+
+    fn add(db: &mut Db, user_id: i64, url: &str, tags: &[&str]) -> i64 {
+        db.scope(user_id, true).unwrap();
+        db.insert_bookmark(&Bookmark {
+            name: url.to_string(),
+            url: url.to_string(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            ..Default::default()
+        })
+        .unwrap()
+        .id
+    }
+
+    fn visible(db: &mut Db, user_id: i64, show_private: bool) -> Vec<String> {
+        db.scope(user_id, show_private).unwrap();
+        let mut urls: Vec<String> = db
+            .get_page(&Page::default())
+            .unwrap()
+            .into_iter()
+            .map(|bookmark| bookmark.url)
+            .collect();
+        urls.sort();
+        urls
+    }
+
+    fn users<const N: usize>(db: &Db, names: [&str; N]) -> [i64; N] {
+        names.map(|name| db.set_password(name, "hash").unwrap())
+    }
+
+    #[test]
+    fn users_see_only_their_own_bookmarks() {
+        let mut db = db_with(&[]);
+        let [alice, bob] = users(&db, ["alice", "bob"]);
+        add(&mut db, alice, "a", &["rust"]);
+        add(&mut db, bob, "b", &["rust"]);
+        assert_eq!(visible(&mut db, alice, true), ["a"]);
+        assert_eq!(visible(&mut db, bob, true), ["b"]);
+    }
+
+    #[test]
+    fn shared_tag_shows_only_members_bookmarks() {
+        let mut db = db_with(&[]);
+        let [alice, bob, carol] = users(&db, ["alice", "bob", "carol"]);
+        db.share_tag("team", alice).unwrap();
+        db.share_tag("team", bob).unwrap();
+        add(&mut db, alice, "a", &["team"]);
+        add(&mut db, bob, "b", &["team"]);
+        add(&mut db, bob, "b-other", &["rust"]);
+        add(&mut db, carol, "c", &["team"]);
+        assert_eq!(visible(&mut db, alice, true), ["a", "b"]);
+        assert_eq!(visible(&mut db, bob, true), ["a", "b", "b-other"]);
+        assert_eq!(visible(&mut db, carol, true), ["c"]);
+    }
+
+    #[test]
+    fn shared_tag_never_shows_other_members_private_bookmarks() {
+        let mut db = db_with(&[]);
+        let [alice, bob] = users(&db, ["alice", "bob"]);
+        db.share_tag("team", alice).unwrap();
+        db.share_tag("team", bob).unwrap();
+        add(&mut db, bob, "b", &["team"]);
+        add(&mut db, bob, "b-private", &["team", "private"]);
+        assert_eq!(visible(&mut db, alice, true), ["b"]);
+        assert_eq!(visible(&mut db, bob, true), ["b", "b-private"]);
+    }
+
+    #[test]
+    fn anonymous_scope_hides_private_bookmarks() {
+        let mut db = db_with(&[("a", &["rust"]), ("a-private", &["private"])]);
+        assert_eq!(visible(&mut db, 0, false), ["a"]);
+        assert_eq!(visible(&mut db, 0, true), ["a", "a-private"]);
+    }
+
+    #[test]
+    fn writes_need_ownership() {
+        let mut db = db_with(&[]);
+        let [alice, bob] = users(&db, ["alice", "bob"]);
+        db.share_tag("team", alice).unwrap();
+        db.share_tag("team", bob).unwrap();
+        let id = add(&mut db, alice, "a", &["team"]);
+        let own = add(&mut db, bob, "b", &[]);
+
+        // Bob sees the shared bookmark but can't change it
+        db.scope(bob, true).unwrap();
+        let bookmark = db.get_bookmark_by_id(id).unwrap();
+        assert!(
+            db.update_bookmark(&Bookmark {
+                name: "changed".to_string(),
+                ..bookmark
+            })
+            .is_err()
+        );
+        assert!(db.set_tag("rust", id).is_err());
+        assert!(db.delete_bookmark(&[own, id]).is_err());
+        assert_eq!(db.delete_tag("team").unwrap(), 0);
+
+        db.scope(alice, true).unwrap();
+        let bookmark = db.get_bookmark_by_id(id).unwrap();
+        assert_eq!(bookmark.name, "a");
+        assert!(bookmark.tags.iter().eq(["team"]));
+        assert_eq!(visible(&mut db, bob, true), ["a", "b"]);
     }
 }
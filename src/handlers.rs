@@ -1,75 +1,245 @@
 use axum::{
     Form, Json,
-    extract::{Path, Query, RawQuery, State},
-    http::header::{CONTENT_DISPOSITION, CONTENT_TYPE},
-    response::{Html, IntoResponse, Redirect},
+    extract::{Multipart, Path, Query, RawQuery, Request, State},
+    http::{
+        HeaderMap, StatusCode,
+        header::{ACCEPT, CONTENT_DISPOSITION, CONTENT_TYPE, HOST, SET_COOKIE},
+    },
+    middleware::Next,
+    response::{Html, IntoResponse, Redirect, Response},
 };
-use minijinja::context;
+use minijinja::{Environment, context};
 
-use std::{collections::HashMap, num::ParseIntError};
+use std::{
+    collections::{BTreeMap, HashMap},
+    num::ParseIntError,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use crate::{
     AppState,
-    types::{Bookmark, MyError, Page},
+    auth::{self, Auth},
+    import,
+    types::{Backup, Bookmark, MyError, Page, SearchQuery, SearchSort},
 };
 
+// Returns the uploaded "file" field and all other fields as text
+async fn read_upload(
+    mut multipart: Multipart,
+) -> Result<(Vec<u8>, HashMap<String, String>), MyError> {
+    let mut file = Vec::new();
+    let mut form = HashMap::new();
+
+    while let Some(field) = multipart.next_field().await? {
+        let name = field.name().unwrap_or_default().to_string();
+        if name == "file" {
+            file = field.bytes().await?.to_vec();
+        } else {
+            form.insert(name, field.text().await?);
+        }
+    }
+
+    Ok((file, form))
+}
+
+async fn imported_page(
+    state: &AppState,
+    auth: Auth,
+    bookmarks: Vec<Bookmark>,
+) -> Result<Html<String>, MyError> {
+    let (bookmarks, favorites) = state
+        .db(auth, |db| {
+            Ok((db.insert_bookmarks(bookmarks)?, db.get_favorites()?))
+        })
+        .await?;
+
+    Ok(Html(state.render_as(
+        auth,
+        "index.html",
+        context! { bookmarks, favorites },
+    )?))
+}
+
 pub async fn add_bookmarks_form(
     State(state): State<AppState>,
+    auth: Auth,
     Form(form): Form<HashMap<String, String>>,
 ) -> Result<Html<String>, MyError> {
-    Ok(Html(state.render("index.html",
-        context! { bookmarks => state.db.lock()?.insert(
-            form.get("urls").ok_or(MyError("no 'urls' field in add_bookmarks_form".to_string()))?,
-            form.get("all_tags").ok_or(MyError("no 'all_tags' field in add_bookmarks_form".to_string()))?,
-        )?, favorites => state.db.lock()?.get_favorites()? },
+    auth.require_write()?;
+    let (bookmarks, favorites) = state
+        .db(auth, move |db| {
+            let urls = form.get("urls").ok_or(MyError::BadRequest(
+                "no 'urls' field in add_bookmarks_form".to_string(),
+            ))?;
+            let all_tags = form.get("all_tags").ok_or(MyError::BadRequest(
+                "no 'all_tags' field in add_bookmarks_form".to_string(),
+            ))?;
+            let bookmarks = match form.get("format").map(String::as_str) {
+                Some("onetab") => db.insert_bookmarks(import::parse_onetab(
+                    urls,
+                    &format!("onetab-{}", import::today()),
+                    all_tags,
+                ))?,
+                _ => db.insert(urls, all_tags)?,
+            };
+
+            Ok((bookmarks, db.get_favorites()?))
+        })
+        .await?;
+
+    Ok(Html(state.render_as(
+        auth,
+        "index.html",
+        context! { bookmarks, favorites },
     )?))
 }
 
+pub async fn import_html(
+    State(state): State<AppState>,
+    auth: Auth,
+    multipart: Multipart,
+) -> Result<Html<String>, MyError> {
+    auth.require_write()?;
+    let (file, form) = read_upload(multipart).await?;
+    let bookmarks = import::parse_netscape(
+        &String::from_utf8_lossy(&file),
+        form.get("all_tags").map_or("", |x| x),
+    );
+
+    imported_page(&state, auth, bookmarks).await
+}
+
+pub async fn import_places(
+    State(state): State<AppState>,
+    auth: Auth,
+    multipart: Multipart,
+) -> Result<Html<String>, MyError> {
+    auth.require_write()?;
+    let (file, form) = read_upload(multipart).await?;
+    let bookmarks = tokio::task::spawn_blocking(move || -> Result<Vec<Bookmark>, MyError> {
+        let path = std::env::temp_dir().join(format!(
+            "places-{}.sqlite",
+            SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos()
+        ));
+        std::fs::write(&path, file)?;
+        let bookmarks = import::read_places(&path, form.get("all_tags").map_or("", |x| x));
+        std::fs::remove_file(&path)?;
+
+        Ok(bookmarks?)
+    })
+    .await??;
+
+    imported_page(&state, auth, bookmarks).await
+}
+
+pub async fn import_session(
+    State(state): State<AppState>,
+    auth: Auth,
+    multipart: Multipart,
+) -> Result<Html<String>, MyError> {
+    auth.require_write()?;
+    let (file, form) = read_upload(multipart).await?;
+    let bookmarks = import::parse_session(
+        &file,
+        &format!("session-{}", import::today()),
+        form.get("all_tags").map_or("", |x| x),
+    )?;
+
+    imported_page(&state, auth, bookmarks).await
+}
+
+pub async fn import_chrome(
+    State(state): State<AppState>,
+    auth: Auth,
+    multipart: Multipart,
+) -> Result<Html<String>, MyError> {
+    auth.require_write()?;
+    let (file, form) = read_upload(multipart).await?;
+    let bookmarks = import::parse_chrome(&file, form.get("all_tags").map_or("", |x| x))?;
+
+    imported_page(&state, auth, bookmarks).await
+}
+
+pub async fn import_csv(
+    State(state): State<AppState>,
+    auth: Auth,
+    multipart: Multipart,
+) -> Result<Html<String>, MyError> {
+    auth.require_write()?;
+    let (file, form) = read_upload(multipart).await?;
+    let bookmarks = import::parse_csv(
+        &String::from_utf8_lossy(&file),
+        form.get("all_tags").map_or("", |x| x),
+    )?;
+
+    imported_page(&state, auth, bookmarks).await
+}
+
 pub async fn update_bookmark_form(
     State(state): State<AppState>,
+    auth: Auth,
     Path(id): Path<i64>,
     Form(form): Form<Bookmark>,
 ) -> Result<Html<String>, MyError> {
-    Ok(Html(state.render(
+    auth.require_write()?;
+    let bookmark = state
+        .db(auth, move |db| {
+            Ok(db.update_bookmark(&Bookmark {
+                id,
+                url: form.url,
+                name: form.name,
+                description: form.description,
+                tags: form.tags,
+                ..Default::default()
+            })?)
+        })
+        .await
+        .map_err(MyError::for_bookmark)?;
+
+    Ok(Html(state.render_as(
+        auth,
         "article.html",
-        context! { bookmark => state.db.lock()?.update_bookmark(&Bookmark {
-            id,
-            url: form.url,
-            name: form.name,
-            description: form.description,
-            tags: form.tags,
-            ..Default::default()
-        })?},
+        context! { bookmark },
     )?))
 }
 
 pub async fn edit_bookmark(
     State(state): State<AppState>,
+    auth: Auth,
     Path(id): Path<i64>,
 ) -> Result<Html<String>, MyError> {
-    Ok(Html(state.render(
-        "edit.html",
-        context! { bookmark => state.db.lock()?.get_bookmark_by_id(id)? },
-    )?))
+    auth.require_write()?;
+    let bookmark = state
+        .db(auth, move |db| Ok(db.get_bookmark_by_id(id)?))
+        .await
+        .map_err(MyError::for_bookmark)?;
+
+    Ok(Html(state.render("edit.html", context! { bookmark })?))
 }
 
 pub async fn delete_bookmark(
     State(state): State<AppState>,
+    auth: Auth,
     RawQuery(rq): RawQuery,
 ) -> Result<Html<String>, MyError> {
+    auth.require_write()?;
     let parsed_ids: Result<Vec<i64>, ParseIntError> = rq
         .unwrap_or_default()
         .split('&')
         .map(|x| x.split_once('=').unwrap_or_default().1.parse())
         .collect();
-    let deleted = state.db.lock()?.delete_bookmark(&parsed_ids?)?;
+    let ids = parsed_ids?;
+    let deleted = state
+        .db(auth, move |db| Ok(db.delete_bookmark(&ids)?))
+        .await
+        .map_err(MyError::for_bookmark)?;
 
     Ok(Html(
         deleted
             .iter()
             .map(|bookmark| {
                 state
-                    .render("article.html", context! { bookmark, deleted => true })
+                    .render_as(auth, "article.html", context! { bookmark, deleted => true })
                     .unwrap_or_default()
             })
             .collect::<String>(),
@@ -78,121 +248,561 @@ pub async fn delete_bookmark(
 
 pub async fn set_tag(
     State(state): State<AppState>,
+    auth: Auth,
     Path((id, tag)): Path<(i64, String)>,
 ) -> Result<Html<String>, MyError> {
-    Ok(Html(state.render(
+    auth.require_write()?;
+    let bookmark = state
+        .db(auth, move |db| Ok(db.set_tag(&tag, id)?))
+        .await
+        .map_err(MyError::for_bookmark)?;
+
+    Ok(Html(state.render_as(
+        auth,
         "article.html",
-        context! { bookmark => state.db.lock()?.set_tag(&tag, id)? },
+        context! { bookmark },
     )?))
 }
 
-pub async fn tags_page(State(state): State<AppState>) -> Result<Html<String>, MyError> {
-    Ok(Html(state.render("tags.html", context! {
-        tags => state.db.lock()?.list_tags()?,
-        favorites => state.db.lock()?.get_favorites()?
-    })?))
+pub async fn tags_page(State(state): State<AppState>, auth: Auth) -> Result<Html<String>, MyError> {
+    let (tags, favorites) = state
+        .db(auth, |db| Ok((db.list_tags()?, db.get_favorites()?)))
+        .await?;
+
+    Ok(Html(
+        state.render("tags.html", context! { tags, favorites })?,
+    ))
 }
 
 pub async fn tag_page(
     State(state): State<AppState>,
+    auth: Auth,
     Path(tag_name): Path<String>,
+    Query(mut page): Query<Page>,
 ) -> Result<Html<String>, MyError> {
-    let bookmarks = state.db.lock()?.get_bookmarks_by_tag(&tag_name)?;
-    let favorites = state.db.lock()?.get_favorites()?;
+    let limit = page.checked(state.config.page_size)?;
+    let p = page.p.unwrap_or_default();
+    let ((bookmarks, number), favorites) = {
+        let tag_name = tag_name.clone();
+        state
+            .db(auth, move |db| {
+                Ok((
+                    db.get_bookmarks_by_tag(&tag_name, &page)?,
+                    db.get_favorites()?,
+                ))
+            })
+            .await?
+    };
 
-    Ok(Html(state.render(
+    Ok(Html(state.render_as(
+        auth,
         "index.html",
-        context! { bookmarks, favorites, tag_name },
+        context! {
+            bookmarks,
+            number,
+            page => p,
+            pages => number.div_ceil(limit),
+            limit,
+            favorites,
+            tag_name
+        },
     )?))
 }
 
 pub async fn rename_tag(
     State(state): State<AppState>,
+    auth: Auth,
     Path(old): Path<String>,
     Form(form): Form<HashMap<String, String>>,
 ) -> Result<Redirect, MyError> {
-    state.db.lock()?.rename_tag(
-        &old,
-        form.get("new")
-            .ok_or(MyError("no 'new' field in form".to_string()))?,
-    )?;
+    auth.require_write()?;
+    state
+        .db(auth, move |db| {
+            Ok(db.rename_tag(
+                &old,
+                form.get("new")
+                    .ok_or(MyError::BadRequest("no 'new' field in form".to_string()))?,
+            )?)
+        })
+        .await?;
     Ok(Redirect::to("/tags"))
 }
 
 pub async fn delete_tag(
     State(state): State<AppState>,
+    auth: Auth,
     Path(name): Path<String>,
 ) -> Result<impl IntoResponse, MyError> {
-    state.db.lock()?.delete_tag(&name)?;
+    auth.require_write()?;
+    state.db(auth, move |db| Ok(db.delete_tag(&name)?)).await?;
     Ok(([("HX-Refresh", "true")], ""))
 }
 
+// Pinged when a bookmark's link is followed, for sorting /all by last visit
+pub async fn visit(
+    State(state): State<AppState>,
+    auth: Auth,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, MyError> {
+    auth.require_write()?;
+    state.db(auth, move |db| Ok(db.visit(id)?)).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
 pub async fn set_favorite(
     State(state): State<AppState>,
+    auth: Auth,
     Path(name): Path<String>,
 ) -> Result<impl IntoResponse, MyError> {
-    state.db.lock()?.set_favorite(&format!("/tags/{name}"))?;
+    auth.require_write()?;
+    state
+        .db(auth, move |db| {
+            Ok(db.set_favorite(&format!("/tags/{name}"))?)
+        })
+        .await?;
     Ok(([("HX-Refresh", "true")], ""))
 }
 
-pub async fn index(State(state): State<AppState>) -> Result<Html<String>, MyError> {
-    Ok(Html(state.render("index.html", context! {
-        bookmarks => state.db.lock()?.get_bookmarks_by_tag("imp")?,
-        favorites => state.db.lock()?.get_favorites()?
-    })?))
+pub async fn index(State(state): State<AppState>, auth: Auth) -> Result<Html<String>, MyError> {
+    let config = state.config.clone();
+    let (bookmarks, favorites) = state
+        .db(auth, move |db| {
+            let bookmarks = match &config.home_query {
+                Some(query) => db.search(&query.parse()?, &Page::default(), true)?.0,
+                None => {
+                    db.get_bookmarks_by_tag(
+                        config.home_tag.as_ref().unwrap_or(&config.tags.imp),
+                        &Page::default(),
+                    )?
+                    .0
+                }
+            };
+
+            Ok((bookmarks, db.get_favorites()?))
+        })
+        .await?;
+
+    Ok(Html(state.render_as(
+        auth,
+        "index.html",
+        context! { bookmarks, favorites },
+    )?))
 }
 
 pub async fn page(
     State(state): State<AppState>,
-    Query(page): Query<Page>,
+    auth: Auth,
+    Query(mut page): Query<Page>,
 ) -> Result<Html<String>, MyError> {
-    let db = state.db.lock()?;
-    let number = db.count_all().unwrap_or_default();
+    let limit = page.checked(state.config.page_size)?;
+    let p = page.p.unwrap_or_default();
+    let sort = page.sort.unwrap_or_default();
+    let (bookmarks, number, favorites) = state
+        .db(auth, move |db| {
+            Ok((
+                db.get_page(&page)?,
+                db.count_all().unwrap_or_default(),
+                db.get_favorites()?,
+            ))
+        })
+        .await?;
 
-    Ok(Html(state.render("index.html", context! {
-        bookmarks => db.get_page(&page)?,
-        number,
-        page => page.p.unwrap_or_default(),
-        pages => number.div_ceil(page.limit.unwrap_or(200)),
-        favorites => db.get_favorites()?
-    })?))
+    Ok(Html(state.render_as(
+        auth,
+        "index.html",
+        context! {
+            bookmarks,
+            number,
+            page => p,
+            pages => number.div_ceil(limit),
+            limit,
+            page_sort => sort,
+            favorites
+        },
+    )?))
 }
 
 pub async fn search(
     State(state): State<AppState>,
+    auth: Auth,
     q: Query<HashMap<String, String>>,
 ) -> Result<Html<String>, MyError> {
-    let db = state.db.lock()?;
-    let bookmarks = match q.iter().next() {
-        Some((k, v)) if k == "d" => db.get_bookmarks_by_date(v)?,
-        Some((k, v)) if k == "q" => db.search(v)?,
-        _ => Vec::new(),
+    let params = q.0.clone();
+    let sort = SearchSort::parse(q.get("sort"))?;
+    // Not a Query<Page>, its sort means something else here
+    let p = q
+        .get("p")
+        .map(|p| p.parse())
+        .transpose()?
+        .unwrap_or_default();
+    let mut page = Page {
+        p: Some(p),
+        limit: q.get("limit").map(|limit| limit.parse()).transpose()?,
+        sort: None,
     };
-    let favorites = db.get_favorites()?;
+    let limit = page.checked(state.config.page_size)?;
+    let ((bookmarks, number), favorites) = state
+        .db(auth, move |db| {
+            let bookmarks = if let Some(query) = params.get("q") {
+                db.search(
+                    &SearchQuery {
+                        sort,
+                        ..query.parse()?
+                    },
+                    &page,
+                    true,
+                )?
+            } else if let Some(date) = params.get("d") {
+                db.get_bookmarks_by_date(date, &page)?
+            } else {
+                (Vec::new(), 0)
+            };
 
-    Ok(Html(
-        state.render("index.html", context! { bookmarks, favorites })?,
+            Ok((bookmarks, db.get_favorites()?))
+        })
+        .await?;
+
+    Ok(Html(state.render_as(
+        auth,
+        "index.html",
+        context! {
+            bookmarks,
+            number,
+            page => p,
+            pages => number.div_ceil(limit),
+            limit,
+            favorites,
+            query => q.get("q"),
+            date => q.get("d"),
+            sort => q.get("q").map(|_| sort)
+        },
+    )?))
+}
+
+// Same result sets as /tags/{name} and /search, selected by ?tag=, ?q= or ?d=
+pub async fn export_markdown(
+    State(state): State<AppState>,
+    auth: Auth,
+    q: Query<HashMap<String, String>>,
+) -> Result<impl IntoResponse, MyError> {
+    let (title, bookmarks) = state
+        .db(auth, move |db| {
+            Ok(if let Some(tag) = q.get("tag") {
+                let (bookmarks, _) = db.get_bookmarks_by_tag(tag, &Page::default())?;
+                (format!("#{tag}"), bookmarks)
+            } else if let Some(query) = q.get("q") {
+                let (bookmarks, _) = db.search(&query.parse()?, &Page::default(), false)?;
+                (query.clone(), bookmarks)
+            } else if let Some(date) = q.get("d") {
+                let (bookmarks, _) = db.get_bookmarks_by_date(date, &Page::default())?;
+                (date.clone(), bookmarks)
+            } else {
+                return Err(MyError::BadRequest(
+                    "no 'tag', 'q' or 'd' parameter".to_string(),
+                ));
+            })
+        })
+        .await?;
+
+    Ok((
+        [
+            (CONTENT_TYPE, "text/markdown; charset=utf-8"),
+            (CONTENT_DISPOSITION, "attachment; filename=\"bookmarks.md\""),
+        ],
+        state.render("markdown.md", context! { title, bookmarks })?,
     ))
 }
 
-pub async fn export_csv(State(state): State<AppState>) -> Result<impl IntoResponse, MyError> {
+pub async fn export_csv(
+    State(state): State<AppState>,
+    auth: Auth,
+) -> Result<impl IntoResponse, MyError> {
     Ok((
         [
             (CONTENT_TYPE, "text/csv; charset=utf-8"),
             (CONTENT_DISPOSITION, "attachment; filename=\"export.csv\""),
         ],
-        state.db.lock()?.export_csv()?,
+        state.db(auth, |db| Ok(db.export_csv()?)).await?,
+    ))
+}
+
+pub async fn export_json(
+    State(state): State<AppState>,
+    auth: Auth,
+) -> Result<impl IntoResponse, MyError> {
+    Ok((
+        [
+            (CONTENT_TYPE, "application/json"),
+            (CONTENT_DISPOSITION, "attachment; filename=\"export.json\""),
+        ],
+        serde_json::to_string_pretty(&state.db(auth, |db| Ok(db.export_json()?)).await?)?,
+    ))
+}
+
+// Every tag becomes a folder, or with as_tags a flat list with TAGS attributes
+pub fn render_netscape(
+    env: &Environment<'static>,
+    bookmarks: &[Bookmark],
+    as_tags: bool,
+) -> Result<String, MyError> {
+    let template = env.get_template("netscape.html")?;
+    if as_tags {
+        return Ok(template.render(context! { bookmarks })?);
+    }
+
+    let mut folders: BTreeMap<&str, Vec<&Bookmark>> = BTreeMap::new();
+    let mut untagged: Vec<&Bookmark> = Vec::new();
+    for bookmark in bookmarks {
+        if bookmark.tags.is_empty() {
+            untagged.push(bookmark);
+        }
+        for tag in &bookmark.tags {
+            folders.entry(tag).or_default().push(bookmark);
+        }
+    }
+
+    Ok(template.render(context! { folders, untagged })?)
+}
+
+pub async fn export_html(
+    State(state): State<AppState>,
+    auth: Auth,
+    q: Query<HashMap<String, String>>,
+) -> Result<impl IntoResponse, MyError> {
+    let bookmarks = state.db(auth, |db| Ok(db.export_json()?.bookmarks)).await?;
+
+    Ok(netscape_response(render_netscape(
+        &state.env,
+        &bookmarks,
+        q.get("mode").is_some_and(|mode| mode == "tags"),
+    )?))
+}
+
+fn netscape_response(body: String) -> impl IntoResponse {
+    (
+        [
+            (CONTENT_TYPE, "text/html; charset=utf-8"),
+            (
+                CONTENT_DISPOSITION,
+                "attachment; filename=\"bookmarks.html\"",
+            ),
+        ],
+        body,
+    )
+}
+
+pub async fn import_json(
+    State(state): State<AppState>,
+    auth: Auth,
+    multipart: Multipart,
+) -> Result<Html<String>, MyError> {
+    auth.require_write()?;
+    let (file, _) = read_upload(multipart).await?;
+    let backup: Backup = serde_json::from_slice(&file)?;
+
+    let (bookmarks, favorites) = state
+        .db(auth, move |db| {
+            Ok((db.import_json(&backup)?, db.get_favorites()?))
+        })
+        .await?;
+
+    Ok(Html(state.render_as(
+        auth,
+        "index.html",
+        context! { bookmarks, favorites },
+    )?))
+}
+
+fn feed_response(
+    state: &AppState,
+    headers: &HeaderMap,
+    path: &str,
+    tag_name: Option<&str>,
+    bookmarks: Vec<Bookmark>,
+) -> Result<impl IntoResponse + use<>, MyError> {
+    let base = format!(
+        "http://{}",
+        headers
+            .get(HOST)
+            .and_then(|host| host.to_str().ok())
+            .unwrap_or("localhost:3000")
+    );
+
+    Ok((
+        [(CONTENT_TYPE, "application/atom+xml; charset=utf-8")],
+        state.render("feed.xml", context! { base, path, tag_name, bookmarks })?,
     ))
 }
 
-pub async fn all_tags(State(app_state): State<AppState>) -> Result<Json<Vec<String>>, MyError> {
+pub async fn feed(
+    State(state): State<AppState>,
+    auth: Auth,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, MyError> {
+    let bookmarks = state
+        .db(auth, |db| {
+            // Feeds are read by unauthenticated readers, never show private bookmarks
+            db.scope(db.user_id(), false)?;
+            Ok(db.get_page(&Page {
+                p: None,
+                limit: Some(50),
+                sort: None,
+            })?)
+        })
+        .await?;
+
+    feed_response(&state, &headers, "/feed.atom", None, bookmarks)
+}
+
+pub async fn tag_feed(
+    State(state): State<AppState>,
+    auth: Auth,
+    headers: HeaderMap,
+    Path(tag_name): Path<String>,
+) -> Result<impl IntoResponse, MyError> {
+    let bookmarks = {
+        let tag_name = tag_name.clone();
+        state
+            .db(auth, move |db| {
+                db.scope(db.user_id(), false)?;
+                Ok(db.get_bookmarks_by_tag(&tag_name, &Page::default())?.0)
+            })
+            .await?
+    };
+
+    feed_response(
+        &state,
+        &headers,
+        &format!("/tags/{tag_name}/feed.atom"),
+        Some(&tag_name),
+        bookmarks,
+    )
+}
+
+pub async fn all_tags(
+    State(app_state): State<AppState>,
+    auth: Auth,
+) -> Result<Json<Vec<String>>, MyError> {
     Ok(Json(
         app_state
-            .db
-            .lock()?
-            .list_tags()?
+            .db(auth, |db| Ok(db.list_tags()?))
+            .await?
             .into_iter()
             .map(|tag| tag.tag_name)
             .collect(),
     ))
 }
+
+pub async fn login_page(
+    State(state): State<AppState>,
+    auth: Auth,
+) -> Result<Html<String>, MyError> {
+    Ok(Html(state.render(
+        "login.html",
+        context! {
+            authenticated => auth.authenticated(),
+            show_private => auth.show_private()
+        },
+    )?))
+}
+
+pub async fn login(
+    State(state): State<AppState>,
+    Form(form): Form<HashMap<String, String>>,
+) -> Result<impl IntoResponse, MyError> {
+    let token = auth::new_token();
+    let hash = auth::token_hash(&token);
+    state
+        .db(Auth::Anonymous, move |db| {
+            let name = form.get("name").map_or("", |x| x);
+            let password = form.get("password").map_or("", |x| x);
+            match db.get_user(name)? {
+                Some((id, password_hash)) if auth::verify_password(password, &password_hash) => {
+                    Ok(db.create_session(&hash, id, auth::SESSION_MAX_AGE)?)
+                }
+                _ => Err(MyError::Unauthorized("Wrong name or password".to_string())),
+            }
+        })
+        .await?;
+
+    Ok((
+        [(
+            SET_COOKIE,
+            auth::set_session_cookie(&token, auth::SESSION_MAX_AGE),
+        )],
+        Redirect::to("/"),
+    ))
+}
+
+pub async fn logout(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, MyError> {
+    if let Some(token) = auth::session_cookie(&headers) {
+        let hash = auth::token_hash(&token);
+        state
+            .db(Auth::Anonymous, move |db| Ok(db.delete_session(&hash)?))
+            .await?;
+    }
+
+    Ok((
+        [
+            (SET_COOKIE, auth::set_session_cookie("", 0)),
+            (SET_COOKIE, auth::set_show_private_cookie(false)),
+        ],
+        Redirect::to("/login"),
+    ))
+}
+
+pub async fn show_private(
+    auth: Auth,
+    Form(form): Form<HashMap<String, String>>,
+) -> Result<impl IntoResponse, MyError> {
+    if !auth.authenticated() {
+        return Err(MyError::Unauthorized(
+            "Log in to see private bookmarks".to_string(),
+        ));
+    }
+
+    Ok((
+        [(
+            SET_COOKIE,
+            auth::set_show_private_cookie(form.get("show").is_some_and(|x| x == "1")),
+        )],
+        Redirect::to("/login"),
+    ))
+}
+
+// Errors leave handlers as plain text, browsers get an error page and API clients JSON
+pub async fn error_page(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let accept = request
+        .headers()
+        .get(ACCEPT)
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let api = request.uri().path().starts_with("/api/");
+    let response = next.run(request).await;
+    let Some(err) = response.extensions().get::<MyError>().cloned() else {
+        return response;
+    };
+    let status = err.status();
+
+    if api || accept.contains("application/json") {
+        (status, Json(serde_json::json!({ "error": err.message() }))).into_response()
+    } else if accept.contains("text/html") {
+        match state.render(
+            "error.html",
+            context! {
+                status => status.as_u16(),
+                reason => status.canonical_reason(),
+                message => err.message()
+            },
+        ) {
+            Ok(page) => (status, Html(page)).into_response(),
+            Err(_) => response,
+        }
+    } else {
+        response
+    }
+}
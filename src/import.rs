@@ -0,0 +1,480 @@
+use rusqlite::{Connection, OpenFlags};
+use serde::Deserialize;
+use time::{Date, OffsetDateTime, UtcOffset, format_description::well_known::Rfc3339};
+
+use std::collections::{BTreeSet, HashMap};
+
+use crate::types::{Bookmark, MyError};
+
+const PLACES_ROOTS: [&str; 6] = [
+    "root________",
+    "menu________",
+    "toolbar_____",
+    "unfiled_____",
+    "mobile______",
+    "tags________",
+];
+
+pub fn today() -> Date {
+    OffsetDateTime::now_utc()
+        .to_offset(UtcOffset::current_local_offset().unwrap_or(UtcOffset::UTC))
+        .date()
+}
+
+pub fn to_tag(name: &str) -> String {
+    name.trim()
+        .to_lowercase()
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<&str>>()
+        .join("-")
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    match entity {
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "amp" => Some('&'),
+        _ => match entity.strip_prefix("#x").or(entity.strip_prefix("#X")) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok(),
+            None => entity.strip_prefix('#')?.parse().ok(),
+        }
+        .and_then(char::from_u32),
+    }
+}
+
+fn decode_entities(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(start) = rest.find('&') {
+        res.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        match rest
+            .find(';')
+            .filter(|&end| end <= 10)
+            .and_then(|end| Some((decode_entity(&rest[1..end])?, end)))
+        {
+            Some((c, end)) => {
+                res.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                res.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    res.push_str(rest);
+
+    res
+}
+
+fn attribute<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let start = tag.to_ascii_uppercase().find(&format!(" {name}=\""))? + name.len() + 3;
+    tag[start..].split_once('"').map(|(value, _)| value)
+}
+
+fn tags_with(tags: impl IntoIterator<Item = String>, tags_for_all: &str) -> BTreeSet<String> {
+    tags.into_iter()
+        .chain(tags_for_all.split(' ').map(String::from))
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
+// Netscape bookmark file: <DT><H3>folder</H3><DL><p> ... </DL><p>, <DT><A HREF ADD_DATE TAGS>name</A>, <DD>description
+pub fn parse_netscape(input: &str, tags_for_all: &str) -> Vec<Bookmark> {
+    let mut res: Vec<Bookmark> = Vec::new();
+    let mut folders: Vec<Option<String>> = Vec::new();
+    let mut pending_folder: Option<String> = None;
+    let mut last_was_link = false;
+
+    for chunk in input.split('<').skip(1) {
+        let Some((tag, text)) = chunk.split_once('>') else {
+            continue;
+        };
+        let tag_name = tag
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_uppercase();
+
+        match tag_name.as_str() {
+            "H3" => {
+                // toolbar and "other bookmarks" roots are not meaningful tags
+                pending_folder = (attribute(tag, "PERSONAL_TOOLBAR_FOLDER").is_none()
+                    && attribute(tag, "UNFILED_BOOKMARKS_FOLDER").is_none())
+                .then(|| to_tag(&decode_entities(text)));
+                last_was_link = false;
+            }
+            "DL" => folders.push(pending_folder.take()),
+            "/DL" => {
+                folders.pop();
+                last_was_link = false;
+            }
+            "A" => {
+                let Some(url) = attribute(tag, "HREF").map(decode_entities) else {
+                    continue;
+                };
+                if url.is_empty() || url.starts_with("place:") {
+                    last_was_link = false;
+                    continue;
+                }
+                let name = decode_entities(text.trim());
+
+                res.push(Bookmark {
+                    name: if name.is_empty() { url.clone() } else { name },
+                    url,
+                    creation_time: attribute(tag, "ADD_DATE")
+                        .and_then(|d| d.parse().ok())
+                        .unwrap_or_default(),
+                    tags: tags_with(
+                        folders.iter().flatten().cloned().chain(
+                            attribute(tag, "TAGS")
+                                .unwrap_or_default()
+                                .split(',')
+                                .map(|t| to_tag(&decode_entities(t))),
+                        ),
+                        tags_for_all,
+                    ),
+                    ..Default::default()
+                });
+                last_was_link = true;
+            }
+            "DD" if last_was_link => {
+                if let Some(b) = res.last_mut() {
+                    b.description = decode_entities(text.trim());
+                }
+                last_was_link = false;
+            }
+            _ => {}
+        }
+    }
+
+    res
+}
+
+// Firefox profile database, opened as immutable so a copy with a stale -wal file still works
+pub fn read_places(path: &std::path::Path, tags_for_all: &str) -> rusqlite::Result<Vec<Bookmark>> {
+    let conn = Connection::open_with_flags(
+        format!("file:{}?immutable=1", path.display()),
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI,
+    )?;
+
+    let folders: HashMap<i64, (i64, String, String)> = conn
+        .prepare("SELECT id, parent, title, guid FROM moz_bookmarks WHERE type = 2")?
+        .query_map([], |row| {
+            Ok((
+                row.get("id")?,
+                (
+                    row.get("parent")?,
+                    row.get::<&str, Option<String>>("title")?
+                        .unwrap_or_default(),
+                    row.get("guid")?,
+                ),
+            ))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let mut place_tags: HashMap<i64, Vec<String>> = HashMap::new();
+    for entry in conn
+        .prepare(
+            "SELECT t.title, b.fk FROM moz_bookmarks b JOIN moz_bookmarks t ON t.id = b.parent
+             WHERE b.type = 1 AND t.parent = (SELECT id FROM moz_bookmarks WHERE guid = 'tags________')",
+        )?
+        .query_map([], |row| Ok((row.get::<&str, String>("title")?, row.get("fk")?)))?
+    {
+        let (tag, fk) = entry?;
+        place_tags.entry(fk).or_default().push(to_tag(&tag));
+    }
+
+    let folder_tags = |mut parent: i64| {
+        let mut res = Vec::new();
+        while let Some((grandparent, title, guid)) = folders.get(&parent) {
+            if !PLACES_ROOTS.contains(&guid.as_str()) {
+                res.push(to_tag(title));
+            }
+            parent = *grandparent;
+        }
+        res
+    };
+
+    conn.prepare(
+        "SELECT b.parent, b.fk, b.title, b.dateAdded, p.url FROM moz_bookmarks b
+         JOIN moz_places p ON p.id = b.fk
+         WHERE b.type = 1 AND b.parent NOT IN (SELECT id FROM moz_bookmarks WHERE parent =
+             (SELECT id FROM moz_bookmarks WHERE guid = 'tags________'))
+         ORDER BY b.dateAdded",
+    )?
+    .query_map([], |row| {
+        let url: String = row.get("url")?;
+        let name: String = row
+            .get::<&str, Option<String>>("title")?
+            .unwrap_or_default();
+        let fk: i64 = row.get("fk")?;
+
+        Ok(Bookmark {
+            name: if name.is_empty() { url.clone() } else { name },
+            url,
+            creation_time: row.get::<&str, i64>("dateAdded")? / 1_000_000,
+            tags: tags_with(
+                folder_tags(row.get("parent")?)
+                    .into_iter()
+                    .chain(place_tags.get(&fk).cloned().unwrap_or_default()),
+                tags_for_all,
+            ),
+            ..Default::default()
+        })
+    })?
+    .filter(|b| b.as_ref().map_or(true, |b| !b.url.starts_with("place:")))
+    .collect()
+}
+
+#[derive(Deserialize)]
+struct Session {
+    windows: Vec<SessionWindow>,
+}
+
+#[derive(Deserialize)]
+struct SessionWindow {
+    tabs: Vec<SessionTab>,
+}
+
+#[derive(Deserialize)]
+struct SessionTab {
+    entries: Vec<SessionEntry>,
+    #[serde(default)]
+    index: usize,
+}
+
+#[derive(Deserialize)]
+struct SessionEntry {
+    url: String,
+    #[serde(default)]
+    title: String,
+}
+
+// sessionstore.jsonlz4/recovery.jsonlz4: "mozLz40\0", u32 LE decompressed size, lz4 block with json
+pub fn parse_session(
+    input: &[u8],
+    session_tag: &str,
+    tags_for_all: &str,
+) -> Result<Vec<Bookmark>, MyError> {
+    let data = input
+        .strip_prefix(b"mozLz40\0")
+        .ok_or(MyError::BadRequest("not a mozLz4 session file".to_string()))?;
+    let session: Session = serde_json::from_slice(&lz4_flex::decompress_size_prepended(data)?)?;
+
+    Ok(session
+        .windows
+        .into_iter()
+        .enumerate()
+        .flat_map(|(i, window)| {
+            window.tabs.into_iter().filter_map(move |tab| {
+                // index is 1-based and points to the currently shown history entry
+                let entry = tab.entries.into_iter().nth(tab.index.saturating_sub(1))?;
+                if entry.url.starts_with("about:") || entry.url.starts_with("moz-extension:") {
+                    return None;
+                }
+
+                Some(Bookmark {
+                    name: if entry.title.is_empty() {
+                        entry.url.clone()
+                    } else {
+                        entry.title
+                    },
+                    url: entry.url,
+                    tags: tags_with(
+                        [session_tag.to_string(), format!("window-{}", i + 1)],
+                        tags_for_all,
+                    ),
+                    ..Default::default()
+                })
+            })
+        })
+        .collect())
+}
+
+#[derive(Deserialize)]
+struct ChromeBookmarks {
+    roots: ChromeRoots,
+}
+
+#[derive(Deserialize)]
+struct ChromeRoots {
+    bookmark_bar: Option<ChromeNode>,
+    other: Option<ChromeNode>,
+    synced: Option<ChromeNode>,
+}
+
+#[derive(Deserialize)]
+struct ChromeNode {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    url: String,
+    #[serde(default)]
+    date_added: String,
+    #[serde(default)]
+    children: Vec<ChromeNode>,
+}
+
+// seconds between 1601-01-01 (WebKit/Windows epoch) and 1970-01-01
+const WEBKIT_EPOCH_OFFSET: i64 = 11_644_473_600;
+
+fn walk_chrome(
+    node: ChromeNode,
+    folders: &mut Vec<String>,
+    tags_for_all: &str,
+    res: &mut Vec<Bookmark>,
+) {
+    if node.kind == "url" {
+        res.push(Bookmark {
+            name: if node.name.is_empty() {
+                node.url.clone()
+            } else {
+                node.name
+            },
+            url: node.url,
+            creation_time: node
+                .date_added
+                .parse::<i64>()
+                .map_or(0, |t| (t / 1_000_000 - WEBKIT_EPOCH_OFFSET).max(0)),
+            tags: tags_with(folders.iter().cloned(), tags_for_all),
+            ..Default::default()
+        });
+    } else {
+        folders.push(to_tag(&node.name));
+        for child in node.children {
+            walk_chrome(child, folders, tags_for_all, res);
+        }
+        folders.pop();
+    }
+}
+
+// Chromium profile "Bookmarks" file, roots bookmark_bar/other/synced are not turned into tags
+pub fn parse_chrome(input: &[u8], tags_for_all: &str) -> Result<Vec<Bookmark>, MyError> {
+    let bookmarks: ChromeBookmarks = serde_json::from_slice(input)?;
+    let mut res = Vec::new();
+
+    let roots = bookmarks.roots;
+    for root in [roots.bookmark_bar, roots.other, roots.synced]
+        .into_iter()
+        .flatten()
+    {
+        for child in root.children {
+            walk_chrome(child, &mut Vec::new(), tags_for_all, &mut res);
+        }
+    }
+
+    Ok(res)
+}
+
+// OneTab export: "url | title" lines, groups of tabs are separated by blank lines
+pub fn parse_onetab(input: &str, group_prefix: &str, tags_for_all: &str) -> Vec<Bookmark> {
+    input
+        .replace("\r\n", "\n")
+        .split("\n\n")
+        .map(str::trim)
+        .filter(|group| !group.is_empty())
+        .enumerate()
+        .flat_map(|(i, group)| {
+            group.lines().filter_map(move |line| {
+                let (url, name) = line.trim().split_once(" | ").unwrap_or((line.trim(), ""));
+                if url.is_empty() {
+                    return None;
+                }
+
+                Some(Bookmark {
+                    name: if name.is_empty() { url } else { name }.to_string(),
+                    url: url.to_string(),
+                    tags: tags_with([format!("{group_prefix}-{}", i + 1)], tags_for_all),
+                    ..Default::default()
+                })
+            })
+        })
+        .collect()
+}
+
+fn csv_records(input: &str) -> Vec<Vec<String>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            (true, '"') => quoted = false,
+            (false, '"') => quoted = true,
+            (false, ',') => record.push(std::mem::take(&mut field)),
+            (false, '\r') => {}
+            (false, '\n') => {
+                record.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut record));
+            }
+            (_, c) => field.push(c),
+        }
+    }
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+
+    records
+}
+
+// Columns are picked by header: title, url, note/description, tags (',' or '|' separated),
+// created/time_added (unix time or RFC 3339), so Raindrop and Pocket exports both work
+pub fn parse_csv(input: &str, tags_for_all: &str) -> Result<Vec<Bookmark>, MyError> {
+    let mut records = csv_records(input).into_iter();
+    let header = records
+        .next()
+        .ok_or(MyError::BadRequest("empty csv file".to_string()))?;
+    let column = |names: &[&str]| {
+        header
+            .iter()
+            .position(|h| names.contains(&h.trim().to_lowercase().as_str()))
+    };
+    let url_column = column(&["url"]).ok_or(MyError::BadRequest(
+        "no 'url' column in csv file".to_string(),
+    ))?;
+    let (title, note, tags, created) = (
+        column(&["title"]),
+        column(&["note", "description"]),
+        column(&["tags"]),
+        column(&["created", "time_added"]),
+    );
+
+    Ok(records
+        .filter_map(|record| {
+            let get = |i: Option<usize>| i.and_then(|i| record.get(i)).map_or("", |x| x.trim());
+            let url = get(Some(url_column)).to_string();
+            if url.is_empty() {
+                return None;
+            }
+            let name = get(title);
+            let created = get(created);
+
+            Some(Bookmark {
+                name: if name.is_empty() { &url } else { name }.to_string(),
+                creation_time: created.parse().unwrap_or_else(|_| {
+                    OffsetDateTime::parse(created, &Rfc3339).map_or(0, |t| t.unix_timestamp())
+                }),
+                description: get(note).to_string(),
+                tags: tags_with(get(tags).split([',', '|']).map(to_tag), tags_for_all),
+                url,
+                ..Default::default()
+            })
+        })
+        .collect())
+}
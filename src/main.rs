@@ -1,28 +1,71 @@
 #![feature(iter_array_chunks)]
 
+mod api;
+mod auth;
+mod cli;
+mod config;
 mod database;
 mod handlers;
+mod import;
+mod migrations;
+mod templates;
 mod types;
 
-use axum::routing::{delete, get, post, put};
-use minijinja::{Environment, path_loader, value::Value};
+use axum::{
+    extract::DefaultBodyLimit,
+    middleware,
+    routing::{delete, get, post, put},
+};
+use minijinja::{Environment, context, value::Value};
 use time::{OffsetDateTime, UtcOffset, format_description::well_known::Rfc3339};
 
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
+use clap::Parser;
+use cli::{Cli, Command};
+use config::Config;
 use handlers::*;
 use types::MyError;
 
 #[derive(Clone)]
 pub struct AppState {
-    pub db: Arc<Mutex<database::Db>>,
+    pub pool: Arc<database::Pool>,
     pub env: Environment<'static>,
+    pub config: Arc<Config>,
 }
 
 impl AppState {
     pub fn render(&self, name: &str, ctx: Value) -> Result<String, MyError> {
         Ok(self.env.get_template(name)?.render(ctx)?)
     }
+
+    // For templates with bookmark actions that depend on what the viewer may do
+    pub fn render_as(&self, auth: auth::Auth, name: &str, ctx: Value) -> Result<String, MyError> {
+        self.render(
+            name,
+            context! { can_write => auth.require_write().is_ok(), ..ctx },
+        )
+    }
+
+    // Runs f on a pooled connection in the blocking thread pool,
+    // scoped to auth's user and with private bookmarks visible as it allows
+    pub async fn db<T, F>(&self, auth: auth::Auth, f: F) -> Result<T, MyError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut database::Db) -> Result<T, MyError> + Send + 'static,
+    {
+        let pool = self.pool.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut db = pool.get();
+            let user_id = match auth {
+                auth::Auth::Anonymous => db.default_user()?,
+                _ => auth.user_id(),
+            };
+            db.scope(user_id, auth.show_private())?;
+            f(&mut db)
+        })
+        .await?
+    }
 }
 
 fn datetimeformat(value: String) -> String {
@@ -34,27 +77,86 @@ fn datetimeformat(value: String) -> String {
         .unwrap_or_default()
 }
 
-#[tokio::main]
-async fn main() {
+fn rfc3339(value: i64) -> String {
+    OffsetDateTime::from_unix_timestamp(value)
+        .unwrap_or(OffsetDateTime::UNIX_EPOCH)
+        .format(&Rfc3339)
+        .unwrap_or_default()
+}
+
+pub fn environment(config: &Config) -> Environment<'static> {
     let mut env = Environment::new();
-    env.set_loader(path_loader("templates"));
+    env.set_loader(templates::loader(config.templates.as_deref()));
     env.add_filter("datetimeformat", datetimeformat);
+    env.add_filter("rfc3339", rfc3339);
+    env.add_global("special_tags", Value::from_serialize(&config.tags));
 
+    env
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    let mut config = match Config::load(cli.config.as_deref()) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Couldn't load config: {err}");
+            std::process::exit(1);
+        }
+    };
+    if let Some(db) = cli.db {
+        config.db = db;
+    }
+    cli.overrides.apply(&mut config);
+
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(config).await,
+        command => {
+            if let Err(err) = cli::run(&config, cli.user.as_deref(), command) {
+                eprintln!("{err}");
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+async fn serve(config: Config) {
     let state = AppState {
-        db: Arc::new(Mutex::new(database::Db::new(
-            &std::env::args().nth(1).unwrap_or("main.db3".to_string()),
-        ))),
-        env,
+        pool: Arc::new(database::Pool::new(
+            &config.db,
+            config.tags.clone(),
+            config.pool_size,
+        )),
+        env: environment(&config),
+        config: Arc::new(config),
     };
-    println!("Server is running at http://localhost:3000");
+    if state
+        .db(auth::Auth::Anonymous, |db| Ok(db.user_count()?))
+        .await
+        .is_ok_and(|count| count == 0)
+    {
+        eprintln!(
+            "No login yet, the web UI is read-only until one is created with `passwd <name>`"
+        );
+    }
+    let address = format!("{}:{}", state.config.address, state.config.port);
+    println!("Server is running at http://{address}");
 
     let app = axum::Router::new()
         .route("/", get(index))
         .route("/all", get(page))
         .route("/tags", get(tags_page))
         .route("/tags/{name}", get(tag_page))
+        .route("/tags/{name}/feed.atom", get(tag_feed))
+        .route("/feed.atom", get(feed))
         .route("/search", get(search))
         .route("/add-bookmarks", post(add_bookmarks_form))
+        .route("/import-html", post(import_html))
+        .route("/import-places", post(import_places))
+        .route("/import-session", post(import_session))
+        .route("/import-chrome", post(import_chrome))
+        .route("/import-json", post(import_json))
+        .route("/import-csv", post(import_csv))
         .route(
             "/edit-bookmark/{id}",
             get(edit_bookmark).post(update_bookmark_form),
@@ -64,11 +166,21 @@ async fn main() {
         .route("/rename-tag/{old}", post(rename_tag))
         .route("/delete-tag/{name}", delete(delete_tag))
         .route("/set-favorite/{name}", put(set_favorite))
+        .route("/visit/{id}", post(visit))
         .route("/export-csv", get(export_csv))
+        .route("/export-json", get(export_json))
+        .route("/export-html", get(export_html))
+        .route("/export-md", get(export_markdown))
         .route("/all-tags", get(all_tags))
+        .route("/login", get(login_page).post(login))
+        .route("/logout", post(logout))
+        .route("/show-private", post(show_private))
+        .nest("/api/v1", api::router())
+        .layer(DefaultBodyLimit::max(64 * 1024 * 1024))
+        .layer(middleware::from_fn_with_state(state.clone(), error_page))
         .with_state(state);
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+    let listener = tokio::net::TcpListener::bind(address).await.unwrap();
     axum::serve(listener, app)
         .await
         .expect("Can't start server!");
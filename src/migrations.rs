@@ -0,0 +1,66 @@
+use rusqlite::{Connection, Result, TransactionBehavior};
+
+// Append only: a migration's position in this list is the schema version it brings
+// the database to, stored in PRAGMA user_version
+const MIGRATIONS: &[(&str, &str)] = &[
+    ("001_init", include_str!("migrations/001_init.sql")),
+    (
+        "002_drop_tags_done_trigger",
+        include_str!("migrations/002_drop_tags_done_trigger.sql"),
+    ),
+    ("003_auth", include_str!("migrations/003_auth.sql")),
+    (
+        "004_multi_user",
+        include_str!("migrations/004_multi_user.sql"),
+    ),
+    (
+        "005_last_visited",
+        include_str!("migrations/005_last_visited.sql"),
+    ),
+];
+
+pub fn version(conn: &Connection) -> Result<usize> {
+    conn.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
+pub fn pending(conn: &Connection) -> Result<Vec<&'static str>> {
+    Ok(MIGRATIONS
+        .iter()
+        .skip(version(conn)?)
+        .map(|(name, _)| *name)
+        .collect())
+}
+
+// Foreign keys are off while migrating so tables can be rebuilt without cascading
+// deletes, and checked before the changes are committed
+pub fn migrate(conn: &mut Connection) -> Result<()> {
+    let version = version(conn)?;
+    if version == MIGRATIONS.len() {
+        return Ok(());
+    }
+
+    conn.pragma_update(None, "foreign_keys", false)?;
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+
+    for (i, (name, sql)) in MIGRATIONS.iter().enumerate().skip(version) {
+        tx.execute_batch(sql)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        eprintln!("Applied migration {name}");
+    }
+
+    let violations: usize =
+        tx.query_row("SELECT count() FROM pragma_foreign_key_check", [], |row| {
+            row.get(0)
+        })?;
+    if violations > 0 {
+        return Err(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_CONSTRAINT_FOREIGNKEY),
+            Some(format!(
+                "{violations} foreign key violations after migrating"
+            )),
+        ));
+    }
+
+    tx.commit()?;
+    conn.pragma_update(None, "foreign_keys", true)
+}
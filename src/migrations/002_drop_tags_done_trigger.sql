@@ -0,0 +1,2 @@
+-- "done" removes "todo" in Db::set_tag, where the tag names are configurable
+DROP TRIGGER IF EXISTS tags_done_ai;
@@ -0,0 +1,21 @@
+CREATE TABLE IF NOT EXISTS users (
+    id              INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    name            TEXT NOT NULL CHECK(name <> '') UNIQUE,
+    password_hash   TEXT NOT NULL
+);
+
+-- Only sha256 hashes of session and api tokens are stored
+CREATE TABLE IF NOT EXISTS sessions (
+    token_hash      TEXT PRIMARY KEY NOT NULL,
+    user_id         INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    expires         INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS api_tokens (
+    id              INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    name            TEXT NOT NULL,
+    token_hash      TEXT NOT NULL UNIQUE,
+    scope           TEXT NOT NULL CHECK(scope IN ('read', 'write')),
+    user_id         INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    creation_time   INTEGER NOT NULL DEFAULT (unixepoch())
+);
@@ -0,0 +1,44 @@
+-- Bookmarks and favorites get an owner, existing ones go to the first login or
+-- stay with user 0 until one is created. A url is unique per user.
+CREATE TABLE bookmarks_new (
+    id              INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    user_id         INTEGER NOT NULL DEFAULT 0,
+    url             TEXT NOT NULL CHECK(url <> ''),
+    name            TEXT NOT NULL,
+    creation_time   INTEGER NOT NULL,
+    description     TEXT NOT NULL,
+    UNIQUE(user_id, url)
+);
+INSERT INTO bookmarks_new (id, user_id, url, name, creation_time, description)
+    SELECT id, coalesce((SELECT min(id) FROM users), 0), url, name, creation_time, description
+    FROM bookmarks;
+DROP TABLE bookmarks;
+ALTER TABLE bookmarks_new RENAME TO bookmarks;
+
+-- Dropped with the old table, bookmarks_fts itself keeps the same rowids
+CREATE TRIGGER bookmarks_ai AFTER INSERT ON bookmarks BEGIN
+    INSERT INTO bookmarks_fts(rowid, name, url, creation_time, description) VALUES (new.id, new.name, new.url, new.creation_time, new.description);
+END;
+CREATE TRIGGER bookmarks_ad AFTER DELETE ON bookmarks BEGIN
+    INSERT INTO bookmarks_fts(bookmarks_fts, rowid, name, url, creation_time, description) VALUES('delete', old.id, old.name, old.url, old.creation_time, old.description);
+END;
+CREATE TRIGGER bookmarks_au AFTER UPDATE ON bookmarks BEGIN
+    INSERT INTO bookmarks_fts(bookmarks_fts, rowid, name, url, creation_time, description) VALUES('delete', old.id, old.name, old.url, old.creation_time, old.description);
+    INSERT INTO bookmarks_fts(rowid, name, url, creation_time, description) VALUES (new.id, new.name, new.url, new.creation_time, new.description);
+END;
+
+CREATE TABLE favorites_new (
+    user_id         INTEGER NOT NULL DEFAULT 0,
+    path            TEXT NOT NULL,
+    UNIQUE (user_id, path) ON CONFLICT IGNORE
+);
+INSERT INTO favorites_new SELECT coalesce((SELECT min(id) FROM users), 0), path FROM favorites;
+DROP TABLE favorites;
+ALTER TABLE favorites_new RENAME TO favorites;
+
+-- Members of a shared tag see each other's bookmarks with that tag
+CREATE TABLE shared_tags (
+    tag_name        TEXT NOT NULL,
+    user_id         INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    UNIQUE(tag_name, user_id) ON CONFLICT IGNORE
+);
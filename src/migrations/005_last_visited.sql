@@ -0,0 +1,2 @@
+-- Set when a logged-in owner follows the bookmark's link, for sorting /all
+ALTER TABLE bookmarks ADD COLUMN last_visited INTEGER;
@@ -0,0 +1,42 @@
+use minijinja::{Error, path_loader};
+
+use std::path::Path;
+
+const TEMPLATES: &[(&str, &str)] = &[
+    ("article.html", include_str!("../templates/article.html")),
+    ("edit.html", include_str!("../templates/edit.html")),
+    ("error.html", include_str!("../templates/error.html")),
+    ("feed.xml", include_str!("../templates/feed.xml")),
+    ("head.html", include_str!("../templates/head.html")),
+    ("index.html", include_str!("../templates/index.html")),
+    ("login.html", include_str!("../templates/login.html")),
+    ("markdown.md", include_str!("../templates/markdown.md")),
+    ("nav.html", include_str!("../templates/nav.html")),
+    ("netscape.html", include_str!("../templates/netscape.html")),
+    ("style.css", include_str!("../templates/style.css")),
+    ("tags.html", include_str!("../templates/tags.html")),
+    ("tree.html", include_str!("../templates/tree.html")),
+];
+
+// Templates compiled into the binary, files in override_dir take precedence
+pub fn loader(
+    override_dir: Option<&Path>,
+) -> impl Fn(&str) -> Result<Option<String>, Error> + Send + Sync + 'static {
+    let overrides = override_dir.map(path_loader);
+
+    move |name| {
+        if let Some(source) = overrides
+            .as_ref()
+            .map(|load| load(name))
+            .transpose()?
+            .flatten()
+        {
+            return Ok(Some(source));
+        }
+
+        Ok(TEMPLATES
+            .iter()
+            .find(|(template, _)| *template == name)
+            .map(|(_, source)| source.to_string()))
+    }
+}
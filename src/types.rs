@@ -1,32 +1,43 @@
 use axum::{
     http::StatusCode,
-    response::{Html, IntoResponse, Response},
+    response::{IntoResponse, Response},
 };
+use clap::ValueEnum;
+use rusqlite::ffi;
 use serde::{Deserialize, Deserializer, Serialize};
 
-use std::collections::BTreeSet;
+use std::{any::Any, collections::BTreeSet, num::ParseIntError};
 
 #[derive(Debug, Default, Deserialize, Serialize)]
 pub struct Bookmark {
-    #[serde(skip_deserializing)]
+    #[serde(default)]
     pub id: i64,
     pub name: String,
     pub url: String,
-    #[serde(skip_deserializing)]
+    #[serde(default)]
     pub creation_time: i64,
+    #[serde(default)]
     pub description: String,
-    #[serde(deserialize_with = "tags_deserialize")]
+    #[serde(default, deserialize_with = "tags_deserialize")]
     pub tags: BTreeSet<String>,
 }
 
+// Space-separated string from forms or a list from JSON
 fn tags_deserialize<'de, D>(deserializer: D) -> Result<BTreeSet<String>, D::Error>
 where
     D: Deserializer<'de>,
 {
-    Ok(String::deserialize(deserializer)?
-        .split(' ')
-        .map(String::from)
-        .collect())
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Tags {
+        Text(String),
+        List(BTreeSet<String>),
+    }
+
+    Ok(match Tags::deserialize(deserializer)? {
+        Tags::Text(s) => s.split(' ').map(String::from).collect(),
+        Tags::List(tags) => tags,
+    })
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -35,24 +46,444 @@ pub struct Tag {
     pub bookmarks_count: u64,
 }
 
-#[derive(Deserialize)]
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Backup {
+    pub bookmarks: Vec<Bookmark>,
+    pub favorites: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SpecialTags {
+    pub private: String,
+    pub todo: String,
+    pub done: String,
+    pub imp: String,
+}
+
+impl Default for SpecialTags {
+    fn default() -> Self {
+        SpecialTags {
+            private: "private".to_string(),
+            todo: "todo".to_string(),
+            done: "done".to_string(),
+            imp: "imp".to_string(),
+        }
+    }
+}
+
+// Without a limit listings other than get_page return everything
+#[derive(Default, Deserialize)]
 pub struct Page {
     pub p: Option<usize>,
     pub limit: Option<usize>,
-    pub sort: Option<String>,
+    pub sort: Option<PageSort>,
+}
+
+impl Page {
+    // Fills in the default limit for a page from a request and refuses pages
+    // whose LIMIT or OFFSET don't fit in SQLite's integers
+    pub fn checked(&mut self, default_limit: usize) -> Result<usize, MyError> {
+        let limit = self.limit.unwrap_or(default_limit).max(1);
+        self.p
+            .unwrap_or(0)
+            .checked_mul(limit)
+            .and_then(|offset| i64::try_from(offset).ok())
+            .and(i64::try_from(limit).ok())
+            .ok_or(MyError::BadRequest("Page out of range".to_string()))?;
+        self.limit = Some(limit);
+        Ok(limit)
+    }
+
+    // LIMIT and OFFSET, with -1 for no limit. Pages that skipped checked
+    // saturate instead of overflowing.
+    pub fn bounds(&self, default_limit: Option<usize>) -> (i64, i64) {
+        let limit = self.limit.or(default_limit);
+        let offset = self.p.unwrap_or(0).saturating_mul(limit.unwrap_or(0));
+        (
+            limit.map_or(-1, |limit| i64::try_from(limit).unwrap_or(i64::MAX)),
+            i64::try_from(offset).unwrap_or(i64::MAX),
+        )
+    }
+}
+
+// Orders for /all, mapped to fixed ORDER BY clauses in Db::get_page
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PageSort {
+    #[default]
+    Newest,
+    Oldest,
+    Name,
+    // By host, then by the rest of the url
+    Domain,
+    // Most tags first
+    Tags,
+    // Most recently visited first, never visited last
+    Visited,
+}
+
+// Parsed search box input:
+// `rust "exact phrase" #todo -#done site:github.com after:2025-01-01 before:2025-06-01 is:untagged`.
+// A lone `#` turns the words after it into tags, the old `# tag1 tag2` form.
+#[derive(Debug, Default)]
+pub struct SearchQuery {
+    pub terms: Vec<String>,
+    pub tags: Vec<String>,
+    pub not_tags: Vec<String>,
+    pub sites: Vec<String>,
+    pub after: Option<String>,
+    pub before: Option<String>,
+    pub untagged: bool,
+    pub sort: SearchSort,
+}
+
+// Relevance only ranks text queries, without text it means newest first
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchSort {
+    #[default]
+    Relevance,
+    Newest,
+    Oldest,
+    Name,
+}
+
+impl SearchSort {
+    // For ?sort= taken from a map of query parameters
+    pub fn parse(sort: Option<&String>) -> Result<Self, MyError> {
+        sort.map_or(Ok(SearchSort::default()), |sort| {
+            <Self as ValueEnum>::from_str(sort, true)
+                .map_err(|_| MyError::BadRequest(format!("Unknown sort '{sort}'")))
+        })
+    }
 }
 
-pub struct MyError(pub String);
+fn search_date(date: &str) -> Result<String, MyError> {
+    let format = time::format_description::parse_borrowed::<2>("[year]-[month]-[day]")?;
+    time::Date::parse(date, &format)
+        .map(|_| date.to_string())
+        .map_err(|_| MyError::BadRequest(format!("Invalid date '{date}', expected YYYY-MM-DD")))
+}
+
+impl std::str::FromStr for SearchQuery {
+    type Err = MyError;
+
+    fn from_str(query: &str) -> Result<Self, MyError> {
+        let mut res = SearchQuery::default();
+        let mut all_tags = false;
+        let mut chars = query.chars().peekable();
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() {
+                chars.next();
+            } else if c == '"' {
+                chars.next();
+                let phrase: String = chars.by_ref().take_while(|&c| c != '"').collect();
+                if !phrase.trim().is_empty() {
+                    res.terms.push(phrase);
+                }
+            } else {
+                let mut token = String::new();
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    token.push(c);
+                }
+
+                if token == "#" {
+                    all_tags = true;
+                } else if let Some(tag) = token.strip_prefix("-#") {
+                    // A lone -# excludes nothing, like a lone # it isn't a filter
+                    if !tag.is_empty() {
+                        res.not_tags.push(tag.to_lowercase());
+                    }
+                } else if let Some(tag) = token.strip_prefix('#') {
+                    res.tags.push(tag.to_lowercase());
+                } else if all_tags {
+                    res.tags.push(token.to_lowercase());
+                } else if let Some(site) = token.strip_prefix("site:") {
+                    res.sites.push(site.to_lowercase());
+                } else if let Some(date) = token.strip_prefix("after:") {
+                    res.after = Some(search_date(date)?);
+                } else if let Some(date) = token.strip_prefix("before:") {
+                    res.before = Some(search_date(date)?);
+                } else if let Some(flag) = token.strip_prefix("is:") {
+                    match flag {
+                        "untagged" => res.untagged = true,
+                        _ => {
+                            return Err(MyError::BadRequest(format!("Unknown filter 'is:{flag}'")));
+                        }
+                    }
+                } else {
+                    res.terms.push(token);
+                }
+            }
+        }
+
+        Ok(res)
+    }
+}
+
+// The FTS table uses the trigram tokenizer, so every term is a substring match
+// and terms under three characters have to fall back to LIKE
+impl SearchQuery {
+    // Nothing to search for, as with an empty box or a lone #
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+            && self.tags.is_empty()
+            && self.not_tags.is_empty()
+            && self.sites.is_empty()
+            && self.after.is_none()
+            && self.before.is_none()
+            && !self.untagged
+    }
+
+    fn is_short(term: &str) -> bool {
+        term.chars().count() < 3
+    }
+
+    pub fn short_terms(&self) -> impl Iterator<Item = &String> {
+        self.terms.iter().filter(|term| Self::is_short(term))
+    }
+
+    // Each term quoted so no FTS5 syntax gets through, None without any
+    pub fn fts_query(&self) -> Option<String> {
+        let terms: Vec<String> = self
+            .terms
+            .iter()
+            .filter(|term| !Self::is_short(term))
+            .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+            .collect();
+
+        (!terms.is_empty()).then(|| terms.join(" "))
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BookmarkFilter {
+    pub p: Option<usize>,
+    pub limit: Option<usize>,
+    pub tag: Option<String>,
+    pub q: Option<String>,
+    pub d: Option<String>,
+    pub sort: Option<SearchSort>,
+}
+
+#[derive(Serialize)]
+pub struct BookmarkList {
+    pub bookmarks: Vec<Bookmark>,
+    pub total: usize,
+    pub page: usize,
+    pub pages: usize,
+}
 
-impl<E: std::error::Error> From<E> for MyError {
+#[derive(Deserialize)]
+pub struct TagRename {
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+pub struct TagMerge {
+    pub tags: Vec<String>,
+    pub into: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Write,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Write => "write",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiToken {
+    pub id: i64,
+    pub name: String,
+    pub scope: Scope,
+    pub user: String,
+    pub creation_time: i64,
+}
+
+#[derive(Debug, Clone)]
+pub enum MyError {
+    NotFound(String),
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    Conflict(String),
+    Internal(String),
+}
+
+impl MyError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            MyError::NotFound(_) => StatusCode::NOT_FOUND,
+            MyError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            MyError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            MyError::Forbidden(_) => StatusCode::FORBIDDEN,
+            MyError::Conflict(_) => StatusCode::CONFLICT,
+            MyError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // Says which bookmark error the generic database ones were
+    pub fn for_bookmark(self) -> Self {
+        match self {
+            MyError::NotFound(_) => MyError::NotFound("Bookmark not found".to_string()),
+            MyError::Conflict(_) => {
+                MyError::Conflict("A bookmark with this url already exists".to_string())
+            }
+            err => err,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            MyError::NotFound(msg)
+            | MyError::BadRequest(msg)
+            | MyError::Unauthorized(msg)
+            | MyError::Forbidden(msg)
+            | MyError::Conflict(msg)
+            | MyError::Internal(msg) => msg,
+        }
+    }
+}
+
+impl std::fmt::Display for MyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl<E: std::error::Error + 'static> From<E> for MyError {
     fn from(err: E) -> Self {
-        MyError(format!("Error: {err}"))
+        let any: &dyn Any = &err;
+        if let Some(err) = any.downcast_ref::<rusqlite::Error>() {
+            match err {
+                rusqlite::Error::QueryReturnedNoRows => {
+                    return MyError::NotFound("Not found".to_string());
+                }
+                rusqlite::Error::SqliteFailure(failure, msg) => match failure.extended_code {
+                    ffi::SQLITE_CONSTRAINT_UNIQUE => {
+                        return MyError::Conflict("Already exists".to_string());
+                    }
+                    ffi::SQLITE_CONSTRAINT_FOREIGNKEY => {
+                        return MyError::NotFound("Not found".to_string());
+                    }
+                    ffi::SQLITE_CONSTRAINT_CHECK | ffi::SQLITE_CONSTRAINT_NOTNULL => {
+                        return MyError::BadRequest(format!(
+                            "Invalid value: {}",
+                            msg.as_deref().unwrap_or("constraint failed")
+                        ));
+                    }
+                    _ => {}
+                },
+                _ => {}
+            }
+        } else if any.is::<ParseIntError>()
+            || any.is::<lz4_flex::block::DecompressError>()
+            || any
+                .downcast_ref::<serde_json::Error>()
+                .is_some_and(|err| !err.is_io())
+        {
+            return MyError::BadRequest(format!("Invalid input: {err}"));
+        }
+
+        MyError::Internal(format!("Error: {err}"))
     }
 }
 
+// The error travels in the response extensions so the error_page middleware,
+// which can see the Accept header, can turn it into HTML or JSON
 impl IntoResponse for MyError {
     fn into_response(self) -> Response {
-        let MyError(body) = self;
-        (StatusCode::INTERNAL_SERVER_ERROR, Html(body)).into_response()
+        let mut response = (self.status(), self.message().to_string()).into_response();
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(query: &str) -> SearchQuery {
+        query.parse().unwrap()
+    }
+
+    fn bad_request(query: &str) -> bool {
+        matches!(query.parse::<SearchQuery>(), Err(MyError::BadRequest(_)))
+    }
+
+    #[test]
+    fn phrases_and_quotes_are_quoted_for_fts() {
+        assert_eq!(parse("\"exact phrase\"").terms, ["exact phrase"]);
+        assert_eq!(
+            parse("\"unterminated phrase").fts_query().unwrap(),
+            "\"unterminated phrase\""
+        );
+        assert_eq!(parse("it\"s").fts_query().unwrap(), "\"it\"\"s\"");
+        assert_eq!(parse("say \"\" \"  \"").fts_query().unwrap(), "\"say\"");
+    }
+
+    #[test]
+    fn fts_operators_are_literal_text() {
+        let query = parse("rust OR python NOT web NEAR(a b) AND* col:x");
+        assert_eq!(
+            query.fts_query().unwrap(),
+            "\"rust\" \"python\" \"NOT\" \"web\" \"NEAR(a\" \"AND*\" \"col:x\""
+        );
+        assert!(query.short_terms().eq(["OR", "b)"]));
+    }
+
+    #[test]
+    fn not_tags() {
+        let query = parse("#rust -#Done -#wip");
+        assert_eq!(query.tags, ["rust"]);
+        assert_eq!(query.not_tags, ["done", "wip"]);
+        assert!(parse("-#").is_empty());
+        assert_eq!(parse("# rust -#web go").tags, ["rust", "go"]);
+        assert_eq!(parse("# rust -#web go").not_tags, ["web"]);
+    }
+
+    #[test]
+    fn is_filters() {
+        assert!(parse("is:untagged").untagged);
+        assert!(bad_request("is:tagged"));
+        assert!(bad_request("rust is:"));
+        assert!(bad_request("is:UNTAGGED"));
+    }
+
+    #[test]
+    fn dates() {
+        let query = parse("after:2025-01-01 before:2025-06-30");
+        assert_eq!(query.after.as_deref(), Some("2025-01-01"));
+        assert_eq!(query.before.as_deref(), Some("2025-06-30"));
+        for query in [
+            "after:",
+            "after:2025-13-01",
+            "after:2025-02-30",
+            "before:yesterday",
+            "before:2025-1-1",
+            "before:2025-01-01T00:00",
+            "after:'; DROP TABLE bookmarks; --",
+        ] {
+            assert!(bad_request(query), "{query:?}");
+        }
+    }
+
+    #[test]
+    fn short_terms_fall_back_to_like() {
+        let query = parse("go rust a 日本 日本語");
+        assert!(query.short_terms().eq(["go", "a", "日本"]));
+        assert_eq!(query.fts_query().unwrap(), "\"rust\" \"日本語\"");
+        assert_eq!(parse("go c").fts_query(), None);
     }
 }